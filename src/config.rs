@@ -0,0 +1,1050 @@
+//! The gateway configuration.
+//!
+//! The configuration is loaded from a TOML or YAML file (see `config.example.toml`),
+//! or built from the environment variables when no file is given.
+use anyhow::{anyhow, bail, Context, Result as AnyResult};
+use dify_client::response::AppMode;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+/// The gateway configuration.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The server settings.
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// The Dify upstreams, by name.
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+    /// The models exposed to the clients, by name.
+    #[serde(default)]
+    pub models: BTreeMap<String, ModelConfig>,
+    /// Serves the requests for unknown models, instead of rejecting them.
+    pub default_model: Option<String>,
+    /// How the chat messages are turned into a Dify query, unless set by the model.
+    #[serde(default)]
+    pub query: QueryConfig,
+    /// How the tools of the requests are described to the apps, unless set by the model.
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    /// How the JSON `response_format` of the requests is enforced.
+    #[serde(default)]
+    pub response_format: ResponseFormatConfig,
+    /// The client authentication settings.
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// The mapping of OpenAI conversations onto Dify conversations.
+    #[serde(default)]
+    pub conversations: ConversationsConfig,
+    /// The request limits.
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// The rate limits and token quotas of the keys and users.
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+    /// The logging settings.
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// The OpenTelemetry tracing settings.
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    /// The audit log of the chat completions.
+    #[serde(default)]
+    pub audit: AuditConfig,
+}
+
+/// The server settings.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The addresses to listen on, as `host:port`.
+    #[serde(default = "default_listen")]
+    pub listen: Vec<String>,
+    /// The number of worker threads, defaults to the number of CPUs.
+    pub workers: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: default_listen(),
+            workers: None,
+        }
+    }
+}
+
+fn default_listen() -> Vec<String> {
+    vec!["127.0.0.1:3000".into()]
+}
+
+/// A Dify API server.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The base URL of Dify's API.
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    /// The default API key of the models served by this upstream.
+    pub api_key: Option<String>,
+    /// The timeout in seconds for requests to Dify's API, `0` disables it.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_base_url() -> String {
+    "https://api.dify.ai".into()
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// A model exposed to the clients, backed by a Dify app.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// The upstream serving the app, may be omitted when there is only one upstream.
+    pub upstream: Option<String>,
+    /// The API key of the Dify app, defaults to the upstream API key.
+    pub api_key: Option<String>,
+    /// The type of the Dify app.
+    #[serde(default = "default_app_type")]
+    pub app_type: AppMode,
+    /// How the chat messages are turned into a Dify query, overriding the global settings.
+    pub query: Option<QueryConfig>,
+    /// The Dify input variable receiving the system messages, instead of the query.
+    pub system_input: Option<String>,
+    /// Validates the inputs against the variables declared by the app, from Dify's parameters API.
+    #[serde(default = "default_true")]
+    pub validate_inputs: bool,
+    /// How a workflow app is run, only for the `workflow` app type.
+    pub workflow: Option<WorkflowConfig>,
+    /// How the agent thoughts are sent to the clients.
+    #[serde(default)]
+    pub reasoning: ReasoningMode,
+    /// How the tools of the requests are described to the app, overriding the global settings.
+    pub tools: Option<ToolsConfig>,
+}
+
+/// How the agent thoughts and tool observations are sent to the clients.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningMode {
+    /// In the `reasoning_content` field of the message, like DeepSeek.
+    #[default]
+    ReasoningContent,
+    /// Inline in the content, in a `<think>` block.
+    Think,
+    /// Not sent.
+    Hidden,
+}
+
+fn default_app_type() -> AppMode {
+    AppMode::Chat
+}
+
+/// How the chat messages are turned into the inputs of a workflow run, and back from its outputs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WorkflowConfig {
+    /// The input variable receiving the query, empty to not send it.
+    #[serde(default = "default_query_input")]
+    pub query_input: String,
+    /// Input variables rendered from templates, with the `{query}`, `{question}` and `{system}` placeholders.
+    #[serde(default)]
+    pub inputs: BTreeMap<String, String>,
+    /// The output variable holding the answer, defaults to the only output, or `text`, `answer`, `output`.
+    pub output: Option<String>,
+}
+
+impl Default for WorkflowConfig {
+    fn default() -> Self {
+        Self {
+            query_input: default_query_input(),
+            inputs: Default::default(),
+            output: None,
+        }
+    }
+}
+
+fn default_query_input() -> String {
+    "query".into()
+}
+
+/// How the chat messages are turned into a Dify query.
+/// The unset fields fall back to the global settings, then to the defaults.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct QueryConfig {
+    /// How the messages are sent, defaults to `history`.
+    pub mode: Option<QueryMode>,
+    /// The template of the `history` mode, with the `{system}`, `{history}` and `{question}` placeholders.
+    /// The system messages are part of `{history}`, unless the template uses `{system}`.
+    pub template: Option<String>,
+    /// The template of each message of `{history}`, with the `{role}` and `{content}` placeholders.
+    pub message_template: Option<String>,
+    /// The separator of the messages of `{history}` and `{system}`.
+    pub separator: Option<String>,
+}
+
+/// How the chat messages are sent in the Dify query.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryMode {
+    /// The history is flattened with the templates, followed by the question.
+    #[default]
+    History,
+    /// Only the last message is sent.
+    LastMessage,
+    /// The messages are sent as a JSON array of `{"role", "content"}` objects.
+    JsonMessages,
+}
+
+/// How the tools of the requests are described to the apps, for the tool calling emulation.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ToolsConfig {
+    /// The prompt describing the tools, with the `{tools}`, `{tool_choice}` and `{query}` placeholders.
+    pub prompt: Option<String>,
+}
+
+/// How the JSON `response_format` of the requests is enforced by the gateway.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseFormatConfig {
+    /// The prompt describing the format, with the `{format}` and `{query}` placeholders.
+    pub prompt: Option<String>,
+    /// The number of times an invalid JSON answer is requested again.
+    #[serde(default = "default_response_format_retries")]
+    pub retries: u32,
+}
+
+impl Default for ResponseFormatConfig {
+    fn default() -> Self {
+        Self {
+            prompt: None,
+            retries: default_response_format_retries(),
+        }
+    }
+}
+
+fn default_response_format_retries() -> u32 {
+    2
+}
+
+/// The client authentication settings.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// How the Bearer tokens of the clients are handled.
+    #[serde(default)]
+    pub mode: AuthMode,
+    /// The keys issued by the gateway, by name, for the `strict` mode.
+    #[serde(default)]
+    pub keys: BTreeMap<String, KeyConfig>,
+}
+
+/// How the Bearer tokens of the clients are handled.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// The Bearer token must be a key issued by the gateway, see `auth.keys`.
+    #[default]
+    Strict,
+    /// The Bearer token is forwarded to Dify as the app API key.
+    Passthrough,
+    /// The Bearer token is ignored, the configured API keys are always used.
+    Disabled,
+}
+
+/// A key issued by the gateway.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    /// The SHA-256 hash of the key in hexadecimal, see the `keygen` and `hash-key` commands.
+    pub hash: String,
+    /// The models the key may use, all of them when empty.
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// The Dify app keys used with this key, by model, instead of the configured ones.
+    #[serde(default)]
+    pub api_keys: BTreeMap<String, String>,
+    /// Rejects the key.
+    #[serde(default)]
+    pub revoked: bool,
+    /// The Unix time in seconds from which the key is rejected.
+    pub expires_at: Option<u64>,
+    /// The rate limits of the key, overriding the `rate_limits.key` ones.
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+}
+
+impl KeyConfig {
+    /// Returns whether the key may use a model.
+    pub fn allows(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|m| m == model)
+    }
+}
+
+/// The mapping of OpenAI conversations onto Dify conversations.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConversationsConfig {
+    /// Continues the Dify conversation when a request extends a previous one,
+    /// instead of sending the whole history in the query.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// The time in seconds a conversation is remembered after its last turn.
+    #[serde(default = "default_conversation_ttl")]
+    pub ttl: u64,
+    /// The maximum number of remembered conversation turns.
+    #[serde(default = "default_conversation_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for ConversationsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl: default_conversation_ttl(),
+            max_entries: default_conversation_max_entries(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_conversation_ttl() -> u64 {
+    3600
+}
+
+fn default_conversation_max_entries() -> usize {
+    10000
+}
+
+/// The request limits.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// The maximum size in bytes of a request body.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// The maximum `n` of a request, each choice being a concurrent Dify request.
+    #[serde(default = "default_max_choices")]
+    pub max_choices: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_size: default_max_body_size(),
+            max_choices: default_max_choices(),
+        }
+    }
+}
+
+/// The rate limits and token quotas of the keys and users.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    /// The limits of each key, shared by all the clients when the authentication is disabled.
+    #[serde(default)]
+    pub key: RateLimits,
+    /// The limits of each `user` of the requests, within a key.
+    #[serde(default)]
+    pub user: RateLimits,
+    /// The file the usage is saved to, so the limits survive restarts.
+    pub state_file: Option<PathBuf>,
+}
+
+/// The limits of a key or a user, unset ones are unlimited.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimits {
+    /// The maximum number of completion requests per minute.
+    pub requests_per_minute: Option<u64>,
+    /// The maximum number of streams running at once.
+    pub concurrent_streams: Option<u64>,
+    /// The maximum number of tokens per UTC day, as reported by Dify.
+    pub tokens_per_day: Option<u64>,
+}
+
+impl RateLimits {
+    /// Returns whether no limit is set.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Returns the limits, with the unset ones taken from `defaults`.
+    pub fn or(&self, defaults: &Self) -> Self {
+        Self {
+            requests_per_minute: self.requests_per_minute.or(defaults.requests_per_minute),
+            concurrent_streams: self.concurrent_streams.or(defaults.concurrent_streams),
+            tokens_per_day: self.tokens_per_day.or(defaults.tokens_per_day),
+        }
+    }
+
+    /// Returns the problems of the limits, `path` locates them in the config.
+    fn check(&self, path: &str) -> Vec<String> {
+        let limits = [
+            ("requests_per_minute", self.requests_per_minute),
+            ("concurrent_streams", self.concurrent_streams),
+            ("tokens_per_day", self.tokens_per_day),
+        ];
+        limits
+            .into_iter()
+            .filter(|(_, limit)| *limit == Some(0))
+            .map(|(name, _)| {
+                format!("{path}.{name}: must be greater than 0, leave it unset for no limit")
+            })
+            .collect()
+    }
+}
+
+fn default_max_body_size() -> usize {
+    2 * 1024 * 1024
+}
+
+fn default_max_choices() -> u64 {
+    8
+}
+
+/// The logging settings.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// The log filter, in `RUST_LOG` syntax. `RUST_LOG` takes precedence when set.
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Writes a JSON access log line per request on the standard output.
+    #[serde(default = "default_true")]
+    pub access_log: bool,
+    /// Leaves the content of the messages out of the logs.
+    #[serde(default)]
+    pub redact_content: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            access_log: true,
+            redact_content: false,
+        }
+    }
+}
+
+fn default_log_level() -> String {
+    "error".into()
+}
+
+/// The OpenTelemetry tracing settings.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// The OTLP/HTTP endpoint the spans are exported to, e.g. `http://localhost:4318/v1/traces`.
+    /// The spans are not exported when unset, the trace context is still propagated to Dify.
+    pub otlp_endpoint: Option<String>,
+    /// The `service.name` of the spans.
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: default_service_name(),
+        }
+    }
+}
+
+fn default_service_name() -> String {
+    "dify-openai-apis".into()
+}
+
+/// The audit log of the chat completions, a JSONL file rotated by size.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    /// The file the chat completions are appended to, the audit log is disabled when unset.
+    pub path: Option<PathBuf>,
+    /// The size in bytes the file is rotated at.
+    #[serde(default = "default_audit_max_size")]
+    pub max_size: u64,
+    /// The number of rotated files kept, as `<path>.1` to `<path>.<max_files>`.
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_size: default_audit_max_size(),
+            max_files: default_audit_max_files(),
+        }
+    }
+}
+
+fn default_audit_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_audit_max_files() -> usize {
+    5
+}
+
+/// The legacy `DIFY_MODELS` entry, which may override the base URL and timeout.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct EnvModelConfig {
+    base_url: Option<String>,
+    api_key: Option<String>,
+    #[serde(default = "default_app_type")]
+    app_type: AppMode,
+    timeout: Option<u64>,
+}
+
+impl Config {
+    /// Returns the config file path given by `--config <path>` or `CONFIG_FILE`.
+    pub fn path_from_args() -> Option<PathBuf> {
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "-c" || arg == "--config" {
+                return args.next().map(PathBuf::from);
+            }
+            if let Some(path) = arg.strip_prefix("--config=") {
+                return Some(PathBuf::from(path));
+            }
+        }
+        env::var("CONFIG_FILE").ok().map(PathBuf::from)
+    }
+
+    /// Loads and validates the config file, the format is chosen by the file extension.
+    pub fn load(path: &Path) -> AnyResult<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file `{}`", path.display()))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let config: Self = match extension {
+            "toml" => toml::from_str(&text).map_err(|e| anyhow!(e)),
+            "yaml" | "yml" => serde_yaml::from_str(&text).map_err(|e| anyhow!(e)),
+            _ => Err(anyhow!("unsupported format, expected .toml, .yaml or .yml")),
+        }
+        .with_context(|| format!("failed to parse config file `{}`", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid config file `{}`", path.display()))?;
+        Ok(config)
+    }
+
+    /// Builds the config from the environment variables.
+    pub fn from_env() -> AnyResult<Self> {
+        let host = env::var("HOST").unwrap_or("127.0.0.1".into());
+        let port = env::var("PORT").unwrap_or("3000".into());
+        let workers = match env::var("WORKERS_NUM") {
+            Ok(workers) => Some(
+                workers
+                    .parse()
+                    .map_err(|_| anyhow!("WORKERS_NUM: invalid number `{workers}`"))?,
+            ),
+            Err(_) => None,
+        };
+        let timeout = match env::var("DIFY_TIMEOUT") {
+            Ok(timeout) => timeout
+                .parse()
+                .map_err(|_| anyhow!("DIFY_TIMEOUT: invalid number `{timeout}`"))?,
+            Err(_) => default_timeout(),
+        };
+        let upstream = UpstreamConfig {
+            base_url: env::var("DIFY_BASE_URL").unwrap_or(default_base_url()),
+            api_key: env::var("DIFY_API_KEY").ok(),
+            timeout,
+        };
+
+        let mut config = Self {
+            server: ServerConfig {
+                listen: vec![format!("{host}:{port}")],
+                workers,
+            },
+            ..Default::default()
+        };
+        if let Ok(keys) = env::var("AUTH_KEYS") {
+            config.auth.keys =
+                serde_json::from_str(&keys).context("AUTH_KEYS: invalid key table")?;
+        }
+        if let Ok(mode) = env::var("AUTH_MODE") {
+            config.auth.mode = serde_json::from_value(mode.clone().into())
+                .map_err(|_| anyhow!("AUTH_MODE: invalid mode `{mode}`"))?;
+        }
+        if config.auth.mode == AuthMode::Strict && config.auth.keys.is_empty() {
+            bail!("AUTH_KEYS: at least one key is required with AUTH_MODE `strict`, the default. Issue keys with `dify-openai-apis keygen`, or set AUTH_MODE=passthrough to forward the Bearer tokens of the clients to Dify");
+        }
+        config.tracing.otlp_endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok();
+        if let Ok(enabled) = env::var("ACCESS_LOG") {
+            config.logging.access_log = enabled
+                .parse()
+                .map_err(|_| anyhow!("ACCESS_LOG: invalid boolean `{enabled}`"))?;
+        }
+        if let Ok(redact) = env::var("LOG_REDACT_CONTENT") {
+            config.logging.redact_content = redact
+                .parse()
+                .map_err(|_| anyhow!("LOG_REDACT_CONTENT: invalid boolean `{redact}`"))?;
+        }
+        config.audit.path = env::var("AUDIT_LOG").ok().map(PathBuf::from);
+        if let Ok(enabled) = env::var("DIFY_CONVERSATIONS") {
+            config.conversations.enabled = enabled
+                .parse()
+                .map_err(|_| anyhow!("DIFY_CONVERSATIONS: invalid boolean `{enabled}`"))?;
+        }
+        match env::var("DIFY_MODELS") {
+            Ok(table) => {
+                let table: BTreeMap<String, EnvModelConfig> =
+                    serde_json::from_str(&table).context("DIFY_MODELS: invalid model table")?;
+                for (name, model) in table {
+                    // models overriding the base URL or timeout get an upstream of their own
+                    let upstream_name = if model.base_url.is_some() || model.timeout.is_some() {
+                        let upstream = UpstreamConfig {
+                            base_url: model.base_url.unwrap_or(upstream.base_url.clone()),
+                            timeout: model.timeout.unwrap_or(upstream.timeout),
+                            ..upstream.clone()
+                        };
+                        config.upstreams.insert(name.clone(), upstream);
+                        name.clone()
+                    } else {
+                        "default".into()
+                    };
+                    let model = ModelConfig {
+                        upstream: Some(upstream_name),
+                        api_key: model.api_key,
+                        app_type: model.app_type,
+                        query: None,
+                        system_input: None,
+                        validate_inputs: true,
+                        workflow: None,
+                        reasoning: Default::default(),
+                        tools: None,
+                    };
+                    config.models.insert(name, model);
+                }
+            }
+            Err(_) => {
+                let name = env::var("DIFY_MODEL").unwrap_or("dify".into());
+                let model = ModelConfig {
+                    upstream: Some("default".into()),
+                    api_key: None,
+                    app_type: default_app_type(),
+                    query: None,
+                    system_input: None,
+                    validate_inputs: true,
+                    workflow: None,
+                    reasoning: Default::default(),
+                    tools: None,
+                };
+                config.models.insert(name.clone(), model);
+                config.default_model = Some(name);
+            }
+        }
+        config.upstreams.insert("default".into(), upstream);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns the upstream name of a model.
+    pub fn model_upstream<'a>(&'a self, model: &'a ModelConfig) -> Option<&'a str> {
+        match model.upstream.as_deref() {
+            Some(upstream) => Some(upstream),
+            None if self.upstreams.len() == 1 => self.upstreams.keys().next().map(|k| k.as_str()),
+            None => None,
+        }
+    }
+
+    /// Validates the config, all the problems are reported at once.
+    pub fn validate(&self) -> AnyResult<()> {
+        let mut errors = Vec::new();
+
+        if self.server.listen.is_empty() {
+            errors.push("server.listen: at least one address is required".to_string());
+        }
+        for (i, addr) in self.server.listen.iter().enumerate() {
+            let valid = addr.parse::<SocketAddr>().is_ok()
+                || addr
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid {
+                errors.push(format!(
+                    "server.listen[{i}]: invalid address `{addr}`, expected `host:port`"
+                ));
+            }
+        }
+        if self.server.workers == Some(0) {
+            errors.push("server.workers: must be greater than 0".to_string());
+        }
+
+        for (name, upstream) in &self.upstreams {
+            let base_url = upstream.base_url.as_str();
+            if !(base_url.starts_with("http://") || base_url.starts_with("https://")) {
+                errors.push(format!(
+                    "upstreams.{name}.base_url: invalid URL `{base_url}`, expected http:// or https://"
+                ));
+            }
+        }
+
+        if self.models.is_empty() {
+            errors.push("models: at least one model is required".to_string());
+        }
+        for (name, model) in &self.models {
+            let upstream = match self.model_upstream(model) {
+                Some(upstream) => upstream,
+                None => {
+                    errors.push(format!(
+                        "models.{name}.upstream: required when there isn't exactly one upstream"
+                    ));
+                    continue;
+                }
+            };
+            let Some(upstream_config) = self.upstreams.get(upstream) else {
+                errors.push(format!(
+                    "models.{name}.upstream: unknown upstream `{upstream}`"
+                ));
+                continue;
+            };
+            let has_api_key = model.api_key.is_some() || upstream_config.api_key.is_some();
+            // the keys allowed on the model may all map it to a Dify app key
+            let keys_map_it = self.auth.mode == AuthMode::Strict
+                && self
+                    .auth
+                    .keys
+                    .values()
+                    .filter(|key| key.allows(name))
+                    .all(|key| key.api_keys.contains_key(name));
+            if !has_api_key && !keys_map_it && self.auth.mode != AuthMode::Passthrough {
+                errors.push(format!(
+                    "models.{name}.api_key: required unless the upstream `{upstream}` has an api_key, the auth keys map the model in api_keys, or auth.mode is `passthrough`"
+                ));
+            }
+        }
+        errors.extend(self.query.check("query"));
+        for (name, model) in &self.models {
+            if let Some(query) = model.query.as_ref() {
+                errors.extend(query.check(&format!("models.{name}.query")));
+            }
+            if model.workflow.is_some() && model.app_type != AppMode::Workflow {
+                errors.push(format!(
+                    "models.{name}.workflow: only allowed with app_type `workflow`"
+                ));
+            }
+        }
+        if let Some(model) = self.default_model.as_deref() {
+            if !self.models.contains_key(model) {
+                errors.push(format!("default_model: unknown model `{model}`"));
+            }
+        }
+
+        if self.auth.mode == AuthMode::Strict && self.auth.keys.is_empty() {
+            errors.push(
+                "auth.keys: at least one key is required with auth.mode `strict`, or set auth.mode to `passthrough` or `disabled`".to_string(),
+            );
+        }
+        let mut hashes = BTreeMap::new();
+        for (name, key) in &self.auth.keys {
+            let hash = key.hash.to_lowercase();
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                errors.push(format!(
+                    "auth.keys.{name}.hash: expected a SHA-256 hash in hexadecimal"
+                ));
+            }
+            if let Some(other) = hashes.insert(hash, name) {
+                errors.push(format!(
+                    "auth.keys.{name}.hash: same key as auth.keys.{other}"
+                ));
+            }
+            let models = key.models.iter().chain(key.api_keys.keys());
+            for model in models.filter(|model| !self.models.contains_key(*model)) {
+                errors.push(format!("auth.keys.{name}: unknown model `{model}`"));
+            }
+            errors.extend(
+                key.rate_limits
+                    .check(&format!("auth.keys.{name}.rate_limits")),
+            );
+        }
+        errors.extend(self.rate_limits.key.check("rate_limits.key"));
+        errors.extend(self.rate_limits.user.check("rate_limits.user"));
+
+        if self.conversations.max_entries == 0 {
+            errors.push("conversations.max_entries: must be greater than 0".to_string());
+        }
+        if let Err(e) = check_log_filter(&self.logging.level) {
+            errors.push(format!("logging.level: {e}"));
+        }
+        if let Some(endpoint) = self.tracing.otlp_endpoint.as_deref() {
+            if !(endpoint.starts_with("http://") || endpoint.starts_with("https://")) {
+                errors.push(format!(
+                    "tracing.otlp_endpoint: invalid URL `{endpoint}`, expected http:// or https://"
+                ));
+            }
+        }
+        if self.audit.max_size == 0 {
+            errors.push("audit.max_size: must be greater than 0".to_string());
+        }
+        if self.audit.max_files == 0 {
+            errors.push("audit.max_files: must be greater than 0".to_string());
+        }
+        if self.limits.max_body_size == 0 {
+            errors.push("limits.max_body_size: must be greater than 0".to_string());
+        }
+        if self.limits.max_choices == 0 {
+            errors.push("limits.max_choices: must be greater than 0".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            bail!(errors.join("\n"))
+        }
+    }
+
+    /// Returns the sections that changed compared to `other` and only apply after a restart.
+    pub fn restart_required(&self, other: &Self) -> Vec<&'static str> {
+        let mut sections = Vec::new();
+        if self.server != other.server {
+            sections.push("server");
+        }
+        if self.logging != other.logging {
+            sections.push("logging");
+        }
+        if self.tracing != other.tracing {
+            sections.push("tracing");
+        }
+        if self.audit != other.audit {
+            sections.push("audit");
+        }
+        if self.limits.max_body_size != other.limits.max_body_size {
+            sections.push("limits.max_body_size");
+        }
+        if self.rate_limits.state_file != other.rate_limits.state_file {
+            sections.push("rate_limits.state_file");
+        }
+        sections
+    }
+}
+
+impl QueryConfig {
+    /// Returns the problems of the templates, `path` locates the settings in the config.
+    fn check(&self, path: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(template) = self.template.as_deref() {
+            if !template.contains("{question}") {
+                errors.push(format!(
+                    "{path}.template: the `{{question}}` placeholder is missing"
+                ));
+            }
+        }
+        if let Some(template) = self.message_template.as_deref() {
+            if !template.contains("{content}") {
+                errors.push(format!(
+                    "{path}.message_template: the `{{content}}` placeholder is missing"
+                ));
+            }
+        }
+        errors
+    }
+}
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// Returns the directives of a log filter in `RUST_LOG` syntax.
+fn log_directives(filter: &str) -> impl Iterator<Item = &str> {
+    // the part after `/` is a regex on the log message
+    let directives = filter.split('/').next().unwrap_or_default();
+    directives
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+}
+
+/// Checks a log filter in `RUST_LOG` syntax, e.g. `info,hyper=warn`.
+fn check_log_filter(filter: &str) -> AnyResult<()> {
+    for directive in log_directives(filter) {
+        let (module, level) = match directive.split_once('=') {
+            Some((module, level)) => (Some(module.trim()), level.trim()),
+            // a bare directive is either a level or a module name
+            None if LOG_LEVELS.contains(&directive.to_lowercase().as_str()) => continue,
+            None => (Some(directive), ""),
+        };
+        if let Some(module) = module {
+            let valid = module.split("::").all(|name| {
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            });
+            if !valid {
+                bail!("invalid module `{module}` in `{directive}`");
+            }
+        }
+        if !level.is_empty() && !LOG_LEVELS.contains(&level.to_lowercase().as_str()) {
+            bail!("invalid log level `{level}` in `{directive}`");
+        }
+    }
+    Ok(())
+}
+
+/// Returns a hint for the bare directives of a log filter close to a level, e.g. `eror`.
+/// They are valid module names, enabling all their logs, but more likely misspelled levels.
+pub fn log_filter_hint(filter: &str) -> Option<String> {
+    log_directives(filter)
+        .filter(|directive| !directive.contains('='))
+        .filter(|directive| !LOG_LEVELS.contains(&directive.to_lowercase().as_str()))
+        .find_map(|directive| {
+            let close = LOG_LEVELS
+                .iter()
+                .find(|level| edit_distance(level, &directive.to_lowercase()) <= 1)?;
+            Some(format!(
+                "the log directive `{directive}` enables all the logs of the module `{directive}`, did you mean the level `{close}`?"
+            ))
+        })
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    const VALID: &str = r#"
+        [upstreams.main]
+        base_url = "http://localhost"
+        api_key = "app-x"
+
+        [models.dify]
+
+        [auth.keys.alice]
+        hash = "70802dc540fb07ee85829780425adbf514db424321263fc85de5eebeaf927cc1"
+    "#;
+
+    #[test]
+    fn edit_distance_counts_edits() {
+        assert_eq!(edit_distance("warn", "warn"), 0);
+        assert_eq!(edit_distance("warn", "warp"), 1);
+        assert_eq!(edit_distance("error", "eror"), 1);
+        assert_eq!(edit_distance("off", "foo"), 3);
+        assert_eq!(edit_distance("", "info"), 4);
+        assert_eq!(edit_distance("débug", "debug"), 1);
+    }
+
+    #[test]
+    fn log_filter_accepts_rust_log_syntax() {
+        for filter in [
+            "error",
+            "INFO",
+            "info,hyper=warn",
+            "dify_openai_apis::server=debug, tower_http=trace",
+            // bare module directives enable all their logs
+            "warp",
+            "foo",
+            "info,warp",
+            "debug/chat.*",
+            "",
+        ] {
+            assert!(check_log_filter(filter).is_ok(), "{filter}");
+        }
+    }
+
+    #[test]
+    fn log_filter_rejects_invalid_directives() {
+        let error = |filter| check_log_filter(filter).unwrap_err().to_string();
+        assert_eq!(
+            error("hyper=loud"),
+            "invalid log level `loud` in `hyper=loud`"
+        );
+        assert_eq!(
+            error("info,a b=debug"),
+            "invalid module `a b` in `a b=debug`"
+        );
+        assert_eq!(
+            error("hyper::=warn"),
+            "invalid module `hyper::` in `hyper::=warn`"
+        );
+    }
+
+    #[test]
+    fn log_filter_hints_misspelled_levels() {
+        let hint = log_filter_hint("eror").unwrap();
+        assert!(
+            hint.contains("`eror`") && hint.contains("`error`"),
+            "{hint}"
+        );
+        assert!(log_filter_hint("hyper=warn,Debg")
+            .unwrap()
+            .contains("`debug`"));
+        for filter in ["error", "foo", "eror=debug", "info,dify_openai_apis"] {
+            assert_eq!(log_filter_hint(filter), None, "{filter}");
+        }
+    }
+
+    #[test]
+    fn validate_accepts_valid_config() {
+        parse(VALID).validate().unwrap();
+    }
+
+    #[test]
+    fn validate_reports_all_errors() {
+        let mut config = parse(VALID);
+        config.server.workers = Some(0);
+        config.logging.level = "hyper=loud".into();
+        config.audit.max_files = 0;
+        config.auth.keys.get_mut("alice").unwrap().hash = "nope".into();
+        let errors = config.validate().unwrap_err().to_string();
+        assert_eq!(
+            errors.lines().collect::<Vec<_>>(),
+            [
+                "server.workers: must be greater than 0",
+                "auth.keys.alice.hash: expected a SHA-256 hash in hexadecimal",
+                "logging.level: invalid log level `loud` in `hyper=loud`",
+                "audit.max_files: must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_requires_keys_in_strict_mode() {
+        let mut config = parse(VALID);
+        config.auth.keys.clear();
+        let errors = config.validate().unwrap_err().to_string();
+        assert!(
+            errors.starts_with("auth.keys: at least one key is required"),
+            "{errors}"
+        );
+        config.auth.mode = AuthMode::Passthrough;
+        config.validate().unwrap();
+    }
+}
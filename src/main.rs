@@ -1,65 +1,227 @@
+mod config;
+mod replay;
 mod server;
-use axum::Router;
-use dify_client::{Client as DifyClient, Config as DifyConfig};
-use std::env;
-use std::time::Duration;
+use axum::{extract::DefaultBodyLimit, Router};
+use config::Config;
+use server::AppState;
+use std::future::IntoFuture;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tokio::{net::TcpListener, runtime};
 
 fn main() {
     let _ = dotenvy::dotenv();
-    env_logger::init();
+    if let Some(code) = run_command() {
+        std::process::exit(code);
+    }
 
-    let workers_num = env::var("WORKERS_NUM")
-        .ok()
-        .and_then(|f| f.parse::<usize>().ok())
-        .unwrap_or(num_cpus::get());
+    let config_path = Config::path_from_args();
+    let config = match config_path.as_deref() {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    };
+    let config = config.unwrap_or_else(|e| {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    });
+
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(config.logging.level.as_str()),
+    )
+    .init();
+    // printed, as a misspelled level likely disables the warnings
+    let filter = std::env::var("RUST_LOG").unwrap_or(config.logging.level.clone());
+    if let Some(hint) = config::log_filter_hint(&filter) {
+        eprintln!("Warning: {}", hint);
+    }
+
+    let workers_num = config.server.workers.unwrap_or(num_cpus::get());
     runtime::Builder::new_multi_thread()
         .worker_threads(workers_num)
         .enable_all()
         .build()
         .unwrap()
-        .block_on(init_server());
+        .block_on(init_server(config, config_path));
 }
 
-async fn init_server() {
-    let host = env::var("HOST").unwrap_or("127.0.0.1".into());
-    let port = env::var("PORT").unwrap_or("3000".into());
-    let server_url = format!("{host}:{port}");
-    let dify_base_url = env::var("DIFY_BASE_URL").unwrap_or("https://api.dify.ai".into());
-    let dify_api_key = env::var("DIFY_API_KEY").unwrap_or("your_api_key".into());
-    let dify_timeout = env::var("DIFY_TIMEOUT")
-        .ok()
-        .and_then(|f| f.parse::<u64>().ok())
-        .unwrap_or(10);
-
-    // dify client
-    let dify = DifyClient::new_with_config(DifyConfig {
-        base_url: dify_base_url.clone(),
-        api_key: dify_api_key.clone(),
-        timeout: Duration::from_secs(dify_timeout),
-    });
+/// Runs a command instead of the server, `keygen`, `hash-key <key>` or `replay <audit.jsonl>`.
+fn run_command() -> Option<i32> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("keygen") => {
+            let key = server::generate_key().unwrap_or_else(|e| {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(1);
+            });
+            println!("Key:  {key}");
+            println!("Hash: {}", server::hash_key(&key));
+            Some(0)
+        }
+        Some("hash-key") => match args.next() {
+            Some(key) => {
+                println!("{}", server::hash_key(&key));
+                Some(0)
+            }
+            None => {
+                eprintln!("Usage: dify-openai-apis hash-key <key>");
+                Some(2)
+            }
+        },
+        Some("replay") => Some(replay::run(args)),
+        _ => None,
+    }
+}
+
+async fn init_server(config: Config, config_path: Option<PathBuf>) {
+    let listen = config.server.listen.clone();
+    let max_body_size = config.limits.max_body_size;
+    show_welcome(&config, config_path.as_deref());
+    server::init_access_log(&config.logging);
+    if let Err(e) = server::init_audit_log(&config.audit, max_body_size) {
+        log::error!("Failed to open the audit log: {:#}", e);
+    }
+    if config.audit.path.is_some() && config.logging.redact_content {
+        log::warn!("The audit log is not redacted, it holds the full messages and answers");
+    }
+    if let Err(e) = server::init_tracing(&config.tracing) {
+        log::error!("Failed to set up the tracing: {:#}", e);
+    }
 
     // shared state
-    let state = server::AppState { dify };
-    let app = Router::new().merge(server::app_routes()).with_state(state);
+    let state = AppState::new(config);
+    let app = Router::new()
+        .merge(server::app_routes())
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .with_state(state.clone());
+
+    maintain_rate_limits(state.clone());
+    if let Some(path) = config_path {
+        watch_config(path, state);
+    }
+
+    let mut servers = Vec::new();
+    for addr in listen {
+        let listener = TcpListener::bind(&addr)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to bind to address {addr}: {e}"));
+        servers.push(axum::serve(listener, app.clone()).into_future());
+    }
+    for result in futures::future::join_all(servers).await {
+        result.expect("Server Error");
+    }
+}
 
-    let listener = TcpListener::bind(&server_url)
-        .await
-        .expect("Failed to bind to address");
+/// Reloads the config file on SIGHUP or when the file is modified.
+/// In-flight requests keep using the config they started with.
+fn watch_config(path: PathBuf, state: AppState) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen to SIGHUP");
+        let (path, state) = (path.clone(), state.clone());
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                log::info!("SIGHUP received, reloading config");
+                reload_config(&path, &state);
+            }
+        });
+    }
 
-    show_welcome(&server_url, &dify_base_url, &dify_api_key, dify_timeout);
+    tokio::spawn(async move {
+        let modified_at = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last_modified: Option<SystemTime> = modified_at(&path);
+        let mut interval = tokio::time::interval(Duration::from_secs(2));
+        loop {
+            interval.tick().await;
+            let modified = modified_at(&path);
+            if modified.is_some() && modified != last_modified {
+                last_modified = modified;
+                log::info!("Config file modified, reloading config");
+                reload_config(&path, &state);
+            }
+        }
+    });
+}
 
-    axum::serve(listener, app).await.expect("Server Error");
+/// Forgets the idle rate limit buckets and saves the others, every 10 seconds.
+fn maintain_rate_limits(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            state.rate_limits.maintain();
+        }
+    });
 }
 
-fn show_welcome(server_url: &str, dify_base_url: &str, dify_api_key: &str, dify_timeout: u64) {
+/// Loads the config file and replaces the current config, which is kept if the new one is invalid.
+fn reload_config(path: &Path, state: &AppState) {
+    let mut config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Failed to reload config, keeping the current one: {:#}", e);
+            return;
+        }
+    };
+    let current = state.gateway();
+    let sections = current.config.restart_required(&config);
+    if !sections.is_empty() {
+        log::warn!(
+            "Config changes of {} only apply after a restart",
+            sections.join(", ")
+        );
+        // keep the settings in effect
+        config.server = current.config.server.clone();
+        config.logging = current.config.logging.clone();
+        config.tracing = current.config.tracing.clone();
+        config.audit = current.config.audit.clone();
+        config.limits.max_body_size = current.config.limits.max_body_size;
+        config.rate_limits.state_file = current.config.rate_limits.state_file.clone();
+    }
+    state.reload(config);
+    log::info!("Config reloaded from {}", path.display());
+}
+
+/// Masks an API key for display, keeping its prefix and last characters, e.g. `app-…abcd`.
+fn mask_key(key: &str) -> String {
+    let chars = key.chars().collect::<Vec<_>>();
+    // the short keys would be given away by their last characters
+    if chars.len() < 16 {
+        return "set".into();
+    }
+    let prefix = key.find('-').filter(|i| *i < 8).map_or("", |i| &key[..=i]);
+    let suffix = chars[chars.len() - 4..].iter().collect::<String>();
+    format!("{prefix}…{suffix}")
+}
+
+fn show_welcome(config: &Config, config_path: Option<&Path>) {
+    let config_source = config_path
+        .map(|p| p.display().to_string())
+        .unwrap_or("environment variables".into());
     println!(
         r#"Welcome to the Dify OpenAI API Server!
 
-- Address Listen: {}
-- Dify Base URL:  {}
-- Dify API Key:   {}
-- Dify Timeout:   {} seconds"#,
-        server_url, dify_base_url, dify_api_key, dify_timeout
-    )
+- Config:         {}
+- Address Listen: {}"#,
+        config_source,
+        config.server.listen.join(", ")
+    );
+    println!("- Upstreams:");
+    for (name, upstream) in &config.upstreams {
+        println!(
+            "  - {}: {} (API Key: {}, Timeout: {} seconds)",
+            name,
+            upstream.base_url,
+            upstream
+                .api_key
+                .as_deref()
+                .map_or("not set".into(), mask_key),
+            upstream.timeout
+        );
+    }
+    println!("- Models:");
+    for (name, model) in &config.models {
+        let upstream = config.model_upstream(model).unwrap_or_default();
+        println!("  - {} => {} ({:?})", name, upstream, model.app_type);
+    }
 }
@@ -0,0 +1,303 @@
+//! The `replay` command: sends the chat completions of an audit log to a gateway again, and
+//! compares the answers with the recorded ones, e.g. to check a change of the Dify apps.
+//!
+//! The answers are compared by choice: the status, the content, the tool calls without their
+//! ids, and the finish reason. A stream is reassembled, as in the audit log.
+use crate::server::StreamAssembler;
+use anyhow::{anyhow, Context, Result as AnyResult};
+use futures::StreamExt;
+use serde_json::Value as JsonValue;
+use std::{collections::BTreeSet, fs};
+
+const USAGE: &str =
+    "Usage: dify-openai-apis replay <audit.jsonl> [--url <gateway URL>] [--key <API key>]";
+
+struct Options {
+    path: String,
+    /// The base URL of the gateway.
+    url: String,
+    /// The API key of the requests, `OPENAI_API_KEY` by default.
+    key: Option<String>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> AnyResult<Self> {
+        let mut path = None;
+        let mut url = "http://127.0.0.1:3000".to_string();
+        let mut key = std::env::var("OPENAI_API_KEY").ok();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{arg}: missing value"));
+            match arg.as_str() {
+                "--url" => url = value()?,
+                "--key" => key = Some(value()?),
+                _ if arg.starts_with("--") => return Err(anyhow!("unknown option `{arg}`")),
+                _ if path.is_none() => path = Some(arg),
+                _ => return Err(anyhow!("unexpected argument `{arg}`")),
+            }
+        }
+        let path = path.ok_or_else(|| anyhow!("the audit log is missing"))?;
+        Ok(Self { path, url, key })
+    }
+}
+
+/// Runs the `replay` command with its arguments, returning the exit code:
+/// `0` if all the answers are the same, `1` otherwise.
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {e}\n{USAGE}");
+            return 2;
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    match runtime.block_on(replay(&options)) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            1
+        }
+    }
+}
+
+/// Replays the records of the audit log, returns whether all the answers are the same.
+async fn replay(options: &Options) -> AnyResult<bool> {
+    // read at once, the replayed requests may be appended to the same audit log
+    let records = fs::read_to_string(&options.path)
+        .with_context(|| format!("failed to read {}", options.path))?;
+    let client = reqwest::Client::new();
+    let (mut same, mut changed, mut failed) = (0, 0, 0);
+    for (number, line) in records.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let name = format!("line {}", number + 1);
+        let record = match serde_json::from_str::<JsonValue>(line) {
+            Ok(record) if record["request"].is_object() => record,
+            _ => {
+                println!("{name}: invalid record");
+                failed += 1;
+                continue;
+            }
+        };
+        let name = match record["request_id"].as_str() {
+            Some(request_id) => format!("{name} ({request_id})"),
+            None => name,
+        };
+        match send(&client, options, &record["request"]).await {
+            Ok((status, response)) => {
+                let diff = compare(&record, status, &response);
+                if diff.is_empty() {
+                    println!("{name}: same");
+                    same += 1;
+                } else {
+                    println!("{name}: changed");
+                    diff.iter().for_each(|line| println!("  {line}"));
+                    changed += 1;
+                }
+            }
+            Err(e) => {
+                println!("{name}: failed: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    let total = same + changed + failed;
+    println!("Replayed {total} requests: {same} same, {changed} changed, {failed} failed");
+    Ok(changed == 0 && failed == 0)
+}
+
+/// Sends a recorded request, returns the status and the answer, a stream being reassembled.
+async fn send(
+    client: &reqwest::Client,
+    options: &Options,
+    request: &JsonValue,
+) -> AnyResult<(u16, JsonValue)> {
+    let url = format!("{}/v1/chat/completions", options.url.trim_end_matches('/'));
+    let mut builder = client.post(url).json(request);
+    if let Some(key) = options.key.as_deref() {
+        builder = builder.bearer_auth(key);
+    }
+    let resp = builder.send().await?;
+    let status = resp.status().as_u16();
+    let is_stream = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    if is_stream {
+        let mut assembler = StreamAssembler::default();
+        let mut body = resp.bytes_stream();
+        while let Some(bytes) = body.next().await {
+            assembler.feed(&bytes?);
+        }
+        return Ok((status, assembler.finish()));
+    }
+    let text = resp.text().await?;
+    let response = serde_json::from_str(&text).unwrap_or(JsonValue::String(text));
+    Ok((status, response))
+}
+
+/// Compares a new answer with the recorded one, returns the differences.
+fn compare(record: &JsonValue, status: u16, response: &JsonValue) -> Vec<String> {
+    let mut diff = Vec::new();
+    let recorded = &record["response"];
+    if record["status"].as_u64() != Some(status.into()) {
+        diff.push(format!("status: {} -> {}", record["status"], status));
+    }
+    if recorded["error"]["code"] != response["error"]["code"] {
+        let (old, new) = (&recorded["error"]["code"], &response["error"]["code"]);
+        diff.push(format!("error: {old} -> {new}"));
+    }
+    let indexes = [recorded, response]
+        .iter()
+        .flat_map(|response| response["choices"].as_array().into_iter().flatten())
+        .filter_map(|choice| choice["index"].as_u64())
+        .collect::<BTreeSet<_>>();
+    for index in indexes {
+        let (old, new) = (choice(recorded, index), choice(response, index));
+        let contents = [old, new].map(|choice| {
+            let message = &choice["message"];
+            let mut content = message["content"].as_str().unwrap_or_default().to_owned();
+            // the ids of the tool calls are generated
+            let calls = message["tool_calls"].as_array().into_iter().flatten();
+            for function in calls
+                .map(|call| &call["function"])
+                .chain([&message["function_call"]])
+            {
+                if let Some(name) = function["name"].as_str() {
+                    let arguments = function["arguments"].as_str().unwrap_or_default();
+                    content.push_str(&format!("\n<tool_call> {name}({arguments})"));
+                }
+            }
+            content
+        });
+        if contents[0] != contents[1] {
+            diff.push(format!("choice {index}:"));
+            diff.extend(diff_lines(&contents[0], &contents[1]));
+        }
+        if old["finish_reason"] != new["finish_reason"] {
+            let (old, new) = (&old["finish_reason"], &new["finish_reason"]);
+            diff.push(format!("choice {index} finish_reason: {old} -> {new}"));
+        }
+    }
+    diff
+}
+
+fn choice(response: &JsonValue, index: u64) -> &JsonValue {
+    response["choices"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|choice| choice["index"].as_u64() == Some(index))
+        .unwrap_or(&JsonValue::Null)
+}
+
+/// Returns the removed and added lines between two texts, by longest common subsequence.
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let (old, new) = (
+        old.lines().collect::<Vec<_>>(),
+        new.lines().collect::<Vec<_>>(),
+    );
+    // common[i][j]: the length of the common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn diff_lines_keeps_common_lines() {
+        assert!(diff_lines("a\nb", "a\nb").is_empty());
+        assert_eq!(diff_lines("a\nb\nc", "a\nc\nd"), ["- b", "+ d"]);
+        assert_eq!(diff_lines("", "a"), ["+ a"]);
+        assert_eq!(diff_lines("a\nb", ""), ["- a", "- b"]);
+        // a changed line is removed then added
+        assert_eq!(diff_lines("x\ny\nz", "x\nY\nz"), ["- y", "+ Y"]);
+    }
+
+    fn completion(content: &str, finish_reason: &str) -> JsonValue {
+        json!({"choices": [{"index": 0, "message": {"content": content}, "finish_reason": finish_reason}]})
+    }
+
+    #[test]
+    fn compare_same_answer() {
+        let record = json!({"status": 200, "response": completion("Hello\nworld", "stop")});
+        assert!(compare(&record, 200, &completion("Hello\nworld", "stop")).is_empty());
+    }
+
+    #[test]
+    fn compare_changed_answer() {
+        let record = json!({"status": 200, "response": completion("Hello\nworld", "stop")});
+        let diff = compare(&record, 200, &completion("Hello\nthere", "length"));
+        assert_eq!(
+            diff,
+            [
+                "choice 0:",
+                "- world",
+                "+ there",
+                "choice 0 finish_reason: \"stop\" -> \"length\""
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_status_and_error() {
+        let record = json!({"status": 200, "response": completion("Hello", "stop")});
+        let response = json!({"error": {"code": "upstream_error"}});
+        let diff = compare(&record, 502, &response);
+        assert_eq!(diff[0], "status: 200 -> 502");
+        assert_eq!(diff[1], "error: null -> \"upstream_error\"");
+        assert_eq!(diff[2], "choice 0:");
+        assert_eq!(diff[3], "- Hello");
+    }
+
+    #[test]
+    fn compare_tool_calls_without_ids() {
+        let calls = |id: &str, arguments: &str| {
+            json!({"choices": [{"index": 0, "finish_reason": "tool_calls", "message": {"content": null,
+                "tool_calls": [{"id": id, "function": {"name": "search", "arguments": arguments}}]}}]})
+        };
+        let record = json!({"status": 200, "response": calls("call_1", "{\"q\":1}")});
+        assert!(compare(&record, 200, &calls("call_2", "{\"q\":1}")).is_empty());
+        let diff = compare(&record, 200, &calls("call_1", "{\"q\":2}"));
+        assert_eq!(
+            diff,
+            [
+                "choice 0:",
+                "- <tool_call> search({\"q\":1})",
+                "+ <tool_call> search({\"q\":2})"
+            ]
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Writes an access log line per request, as JSON on the standard output.
+//!
+//! Each request gets an id, the `x-request-id` header of the client or a generated one, returned
+//! in the same response header. The handlers record the client, the model and the tokens on the
+//! request, and the line is written once the response is sent, or once its stream ends.
+use super::{answer::TokenUsage, auth::Client, registry::ModelRoute};
+use crate::config::LoggingConfig;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    io::Write,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Instant, SystemTime},
+};
+
+/// The header of the request ids.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The logging settings, set at startup.
+static SETTINGS: OnceLock<(bool, bool)> = OnceLock::new();
+
+/// Applies the access log and redaction settings.
+pub fn init_access_log(config: &LoggingConfig) {
+    let _ = SETTINGS.set((config.access_log, config.redact_content));
+}
+
+fn enabled() -> bool {
+    SETTINGS.get().map_or(true, |(access_log, _)| *access_log)
+}
+
+fn redacted() -> bool {
+    SETTINGS.get().is_some_and(|(_, redact)| *redact)
+}
+
+/// Logs the content of the messages, unless it is redacted with `logging.redact_content`.
+pub struct Content<'a, T: ?Sized>(pub &'a T);
+
+impl<T: Debug + ?Sized> Debug for Content<'_, T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if redacted() {
+            write!(f, "[redacted]")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+impl<T: Display + ?Sized> Display for Content<'_, T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if redacted() {
+            write!(f, "[redacted]")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+/// The access log line of a request.
+#[derive(Serialize, Default)]
+struct Record {
+    time: String,
+    request_id: String,
+    method: String,
+    route: String,
+    status: u16,
+    /// The time to the response headers.
+    latency_ms: u64,
+    /// The time to the end of the response, with its stream.
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttft_ms: Option<u64>,
+    stream: bool,
+    key_id: Option<String>,
+    model: Option<String>,
+    /// The Dify app serving the model, by model route, type and base URL.
+    app: Option<String>,
+    app_type: Option<String>,
+    upstream: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completion_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+struct Entry {
+    started: Instant,
+    record: Mutex<Record>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if !enabled() {
+            return;
+        }
+        let record = self.record.get_mut().unwrap();
+        record.time = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        record.duration_ms = self.started.elapsed().as_millis() as u64;
+        if let Ok(line) = serde_json::to_string(record) {
+            let _ = writeln!(std::io::stdout().lock(), "{line}");
+        }
+    }
+}
+
+/// The access log entry of a request, written once all its handles are dropped.
+#[derive(Clone)]
+pub struct AccessLog(Arc<Entry>);
+
+impl AccessLog {
+    fn new(record: Record) -> Self {
+        Self(Arc::new(Entry {
+            started: Instant::now(),
+            record: Mutex::new(record),
+        }))
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Record)) {
+        f(&mut self.0.record.lock().unwrap());
+    }
+
+    /// When the request was received.
+    pub fn started(&self) -> Instant {
+        self.0.started
+    }
+
+    /// The id of the request.
+    pub fn request_id(&self) -> String {
+        self.0.record.lock().unwrap().request_id.clone()
+    }
+
+    /// The client of the request, once authenticated.
+    pub fn key_id(&self) -> Option<String> {
+        self.0.record.lock().unwrap().key_id.clone()
+    }
+
+    /// Records the authenticated client.
+    pub fn client(&self, client: &Client) {
+        self.update(|record| record.key_id = client.id());
+    }
+
+    /// Records the requested model, and the route serving it if known.
+    pub fn model(&self, model: &str, route: Option<&ModelRoute>, stream: bool) {
+        self.update(|record| {
+            record.model = Some(model.to_owned());
+            record.stream = stream;
+            if let Some(route) = route {
+                let app_type = serde_json::to_value(&route.app_type).ok();
+                record.app = Some(route.name.clone());
+                record.app_type = app_type.and_then(|value| value.as_str().map(String::from));
+                record.upstream = Some(route.client.config.base_url.clone());
+            }
+        });
+    }
+
+    /// Records the time to the first token of a stream, on the first call.
+    pub fn first_token(&self) {
+        let elapsed = self.0.started.elapsed().as_millis() as u64;
+        self.update(|record| {
+            record.ttft_ms.get_or_insert(elapsed);
+        });
+    }
+
+    /// Adds the tokens of an answer.
+    pub fn usage(&self, usage: TokenUsage) {
+        self.update(|record| {
+            *record.prompt_tokens.get_or_insert(0) += usage.prompt_tokens;
+            *record.completion_tokens.get_or_insert(0) += usage.completion_tokens;
+            *record.total_tokens.get_or_insert(0) += usage.total_tokens;
+        });
+    }
+}
+
+/// The error code of a response, for the access log.
+#[derive(Clone)]
+struct ErrorCode(&'static str);
+
+/// Labels a response with its error code.
+pub fn with_error(mut response: Response, code: &'static str) -> Response {
+    response.extensions_mut().insert(ErrorCode(code));
+    response
+}
+
+/// Returns a valid request id of the client, or generates one.
+fn request_id(req: &Request) -> String {
+    let valid =
+        |id: &&str| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic());
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(valid);
+    if let Some(id) = id {
+        return id.to_owned();
+    }
+    let mut bytes = [0u8; 16];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        // unique enough for the logs
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        bytes = nanos.to_le_bytes();
+    }
+    let random = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!("req_{random}")
+}
+
+/// Sets the request id and writes the access log line of a request.
+pub async fn log(mut req: Request, next: Next) -> Response {
+    let request_id = request_id(&req);
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| req.uri().path().to_owned());
+    let access = AccessLog::new(Record {
+        request_id: request_id.clone(),
+        method: req.method().to_string(),
+        route,
+        ..Default::default()
+    });
+    req.extensions_mut().insert(access.clone());
+    let mut response = next.run(req).await;
+    let error = response.extensions().get::<ErrorCode>().map(|code| code.0);
+    access.update(|record| {
+        record.status = response.status().as_u16();
+        record.latency_ms = access.0.started.elapsed().as_millis() as u64;
+        record.error = error;
+    });
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,783 @@
+//! Normalizes the Dify answers of the different app types, so they are rendered alike.
+use super::dify::{AgentThought, StreamEvent};
+use anyhow::{anyhow, Result as AnyResult};
+use dify_client::response::ErrorResponse;
+use futures::{stream, Stream, StreamExt};
+use serde_json::Value as JsonValue;
+use std::{collections::HashMap, ops::AddAssign};
+
+/// The output variables holding the answer of a workflow, when the output is not configured.
+const WORKFLOW_OUTPUTS: [&str; 3] = ["text", "answer", "output"];
+
+/// Opens the reasoning of an answer, in the `think` reasoning mode.
+pub const THINK_START: &str = "<think>\n";
+/// Closes the reasoning of an answer, in the `think` reasoning mode.
+pub const THINK_END: &str = "\n</think>\n\n";
+
+/// The token usage of an answer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Reads the usage of the Dify message metadata.
+    pub fn from_metadata(metadata: &JsonValue) -> Self {
+        let usage = metadata.get("usage").unwrap_or(&JsonValue::Null);
+        let tokens = |name: &str| usage.get(name).and_then(|v| v.as_u64()).unwrap_or_default();
+        Self {
+            prompt_tokens: tokens("prompt_tokens"),
+            completion_tokens: tokens("completion_tokens"),
+            total_tokens: tokens("total_tokens"),
+        }
+    }
+
+    /// The usage of a workflow run, which only reports the total.
+    pub fn from_total(total_tokens: u64) -> Self {
+        Self {
+            total_tokens,
+            ..Default::default()
+        }
+    }
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// An event of a streamed answer.
+#[derive(Debug)]
+pub enum AnswerEvent {
+    /// The answer started, with the ids of its Dify message or workflow run.
+    Started {
+        id: String,
+        created: u64,
+        conversation_id: Option<String>,
+        /// The Dify task generating the answer, to stop it.
+        task_id: String,
+    },
+    /// A piece of the answer.
+    Text(String),
+    /// The answer was cut at the maximum number of tokens.
+    Truncated,
+    /// The content moderation replaced the whole answer with a preset reply.
+    Replaced(String),
+    /// A piece of the reasoning of an agent, its thoughts and tool observations.
+    Reasoning(String),
+    /// Progress without an OpenAI counterpart, e.g. a workflow node, sent as an SSE comment.
+    Progress(String),
+    /// The answer is complete.
+    Finished(TokenUsage),
+    /// The answer failed.
+    Error(ErrorResponse),
+}
+
+/// Converts the Dify stream events into answer events.
+pub struct AnswerEvents {
+    /// The output variable of a workflow app, `None` for the chat apps.
+    workflow_output: Option<Option<String>>,
+    started: bool,
+    streamed: bool,
+    /// What was sent of the agent thoughts, by id.
+    thoughts: HashMap<String, ThoughtProgress>,
+}
+
+/// What was sent of an agent thought.
+#[derive(Default)]
+struct ThoughtProgress {
+    thought: String,
+    tool: bool,
+    observation: String,
+}
+
+impl AnswerEvents {
+    /// The answer of a chat, agent or chatflow app, made of the message events.
+    pub fn chat() -> Self {
+        Self {
+            workflow_output: None,
+            started: false,
+            streamed: false,
+            thoughts: HashMap::new(),
+        }
+    }
+
+    /// The answer of a workflow app, made of the `text_chunk` events or the output variable.
+    pub fn workflow(output: Option<String>) -> Self {
+        Self {
+            workflow_output: Some(output),
+            ..Self::chat()
+        }
+    }
+
+    /// Converts a stream of Dify events.
+    pub fn convert_stream(
+        mut self,
+        events: impl Stream<Item = AnyResult<StreamEvent>>,
+    ) -> impl Stream<Item = AnswerEvent> {
+        events.flat_map(move |event| stream::iter(self.convert(event)))
+    }
+
+    /// Converts a Dify stream event.
+    pub fn convert(&mut self, event: AnyResult<StreamEvent>) -> Vec<AnswerEvent> {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                return vec![AnswerEvent::Error(ErrorResponse {
+                    code: "stream_error".into(),
+                    message: e.to_string(),
+                    status: 502,
+                })]
+            }
+        };
+        let mut events = Vec::new();
+        match (event, self.workflow_output.as_ref()) {
+            (StreamEvent::Message(chunk) | StreamEvent::AgentMessage(chunk), None) => {
+                self.start(
+                    &mut events,
+                    chunk.message_id,
+                    chunk.created_at,
+                    chunk.conversation_id,
+                    chunk.task_id,
+                );
+                events.push(AnswerEvent::Text(chunk.answer));
+            }
+            (StreamEvent::MessageReplace(chunk), None) => {
+                self.start(
+                    &mut events,
+                    chunk.message_id,
+                    chunk.created_at,
+                    chunk.conversation_id,
+                    chunk.task_id,
+                );
+                events.push(AnswerEvent::Replaced(chunk.answer));
+            }
+            (StreamEvent::AgentThought(thought), None) => {
+                self.start(
+                    &mut events,
+                    thought.message_id.clone(),
+                    thought.created_at,
+                    thought.conversation_id.clone(),
+                    thought.task_id.clone(),
+                );
+                let reasoning = self.reasoning(thought);
+                if !reasoning.is_empty() {
+                    events.push(AnswerEvent::Reasoning(reasoning));
+                }
+            }
+            (StreamEvent::MessageEnd(end), None) => {
+                self.start(
+                    &mut events,
+                    end.message_id,
+                    end.created_at,
+                    end.conversation_id,
+                    end.task_id,
+                );
+                events.push(AnswerEvent::Finished(TokenUsage::from_metadata(
+                    &end.metadata,
+                )));
+            }
+            (StreamEvent::WorkflowStarted(started), Some(_)) => {
+                let created = started.data.created_at;
+                let (id, task_id) = (started.workflow_run_id, started.task_id);
+                self.start(&mut events, id, created, None, task_id);
+            }
+            (StreamEvent::TextChunk(chunk), Some(_)) => {
+                self.streamed = true;
+                events.push(AnswerEvent::Text(chunk.data.text));
+            }
+            (StreamEvent::WorkflowFinished(finished), Some(_)) => {
+                let run = finished.data;
+                let created = run.created_at;
+                let (id, task_id) = (finished.workflow_run_id, finished.task_id);
+                self.start(&mut events, id, created, None, task_id);
+                if run.status != "succeeded" {
+                    let error = run
+                        .error
+                        .unwrap_or_else(|| format!("workflow {}", run.status));
+                    events.push(AnswerEvent::Error(ErrorResponse {
+                        code: "workflow_failed".into(),
+                        message: error,
+                        status: 500,
+                    }));
+                    return events;
+                }
+                if !self.streamed {
+                    let output = self.workflow_output.as_ref().and_then(|o| o.as_deref());
+                    events.push(AnswerEvent::Text(workflow_output(&run.outputs, output)));
+                }
+                events.push(AnswerEvent::Finished(TokenUsage::from_total(
+                    run.total_tokens,
+                )));
+            }
+            (StreamEvent::NodeStarted(node), _) => {
+                let title = node.data.title;
+                events.push(AnswerEvent::Progress(format!(
+                    "workflow node started: {title}"
+                )));
+            }
+            (StreamEvent::NodeFinished(node), _) => {
+                let title = node.data.title;
+                let status = node.data.status.unwrap_or_default();
+                let progress = format!("workflow node finished: {title} ({status})");
+                events.push(AnswerEvent::Progress(progress));
+            }
+            (StreamEvent::Error(err), _) => events.push(AnswerEvent::Error(err)),
+            (StreamEvent::Ping, _) => events.push(AnswerEvent::Progress("ping".into())),
+            _ => events.push(AnswerEvent::Progress("skip dify message event".into())),
+        }
+        events
+    }
+
+    /// Returns the new part of an agent thought, as reasoning text.
+    fn reasoning(&mut self, thought: AgentThought) -> String {
+        let progress = self.thoughts.entry(thought.id).or_default();
+        let mut reasoning = String::new();
+        if !thought.thought.is_empty() {
+            reasoning.push_str(new_part(&progress.thought, &thought.thought));
+            progress.thought = thought.thought;
+        }
+        if !thought.tool.is_empty() && !progress.tool {
+            progress.tool = true;
+            let input = thought.tool_input;
+            reasoning.push_str(&format!("\n\nCalling `{}`: {input}\n", thought.tool));
+        }
+        if !thought.observation.is_empty() {
+            let observation = new_part(&progress.observation, &thought.observation);
+            if progress.observation.is_empty() {
+                reasoning.push_str("\nObservation: ");
+            }
+            reasoning.push_str(observation);
+            progress.observation = thought.observation;
+        }
+        reasoning
+    }
+
+    /// Emits the start of the answer, once.
+    fn start(
+        &mut self,
+        events: &mut Vec<AnswerEvent>,
+        id: String,
+        created: u64,
+        conversation_id: Option<String>,
+        task_id: String,
+    ) {
+        if !self.started {
+            self.started = true;
+            events.push(AnswerEvent::Started {
+                id,
+                created,
+                conversation_id,
+                task_id,
+            });
+        }
+    }
+}
+
+/// Returns an answer without its leading `<think>` block.
+pub fn strip_think(text: &str) -> &str {
+    let Some(rest) = text.trim_start().strip_prefix("<think>") else {
+        return text;
+    };
+    match rest.split_once("</think>") {
+        Some((_, answer)) => answer.trim_start(),
+        None => text,
+    }
+}
+
+/// Returns what `now` adds to `sent`, or all of it if it doesn't extend it.
+fn new_part<'a>(sent: &str, now: &'a str) -> &'a str {
+    now.strip_prefix(sent).unwrap_or(now)
+}
+
+/// A complete answer, as returned by the blocking APIs or aggregated from a stream.
+#[derive(Debug, Default)]
+pub struct Answer {
+    /// The id of the Dify message or workflow run.
+    pub id: String,
+    pub created: u64,
+    pub conversation_id: Option<String>,
+    pub text: String,
+    /// The agent thoughts and tool observations.
+    pub reasoning: String,
+    pub usage: TokenUsage,
+    /// The answer was cut at the maximum number of tokens.
+    pub truncated: bool,
+    /// The answer was replaced by the content moderation.
+    pub filtered: bool,
+}
+
+impl Answer {
+    /// Aggregates the events of a streamed answer.
+    pub async fn collect(events: impl Stream<Item = AnswerEvent>) -> AnyResult<Self> {
+        let mut answer = Self::default();
+        let mut events = std::pin::pin!(events);
+        while let Some(event) = events.next().await {
+            match event {
+                AnswerEvent::Started {
+                    id,
+                    created,
+                    conversation_id,
+                    ..
+                } => {
+                    answer.id = id;
+                    answer.created = created;
+                    answer.conversation_id = conversation_id;
+                }
+                AnswerEvent::Text(text) => answer.text.push_str(&text),
+                AnswerEvent::Truncated => answer.truncated = true,
+                AnswerEvent::Replaced(text) => {
+                    answer.text = text;
+                    answer.filtered = true;
+                }
+                AnswerEvent::Reasoning(reasoning) => answer.reasoning.push_str(&reasoning),
+                AnswerEvent::Progress(_) => {}
+                AnswerEvent::Finished(usage) => answer.usage = usage,
+                AnswerEvent::Error(err) => return Err(anyhow!(err)),
+            }
+        }
+        Ok(answer)
+    }
+
+    /// Replays the answer as a stream of events.
+    pub fn into_events(self) -> impl Stream<Item = AnswerEvent> {
+        let mut events = vec![AnswerEvent::Started {
+            id: self.id,
+            created: self.created,
+            conversation_id: self.conversation_id,
+            task_id: String::new(),
+        }];
+        if !self.reasoning.is_empty() {
+            events.push(AnswerEvent::Reasoning(self.reasoning));
+        }
+        events.push(match self.filtered {
+            true => AnswerEvent::Replaced(self.text),
+            false => AnswerEvent::Text(self.text),
+        });
+        if self.truncated {
+            events.push(AnswerEvent::Truncated);
+        }
+        events.push(AnswerEvent::Finished(self.usage));
+        stream::iter(events)
+    }
+}
+
+/// Returns the answer of a workflow from its outputs.
+/// Without a configured output variable, the only output is used, then the well-known ones,
+/// then the outputs as JSON.
+pub fn workflow_output(outputs: &JsonValue, output: Option<&str>) -> String {
+    let value = match (output, outputs.as_object()) {
+        (Some(output), _) => outputs.get(output),
+        (None, Some(map)) if map.len() == 1 => map.values().next(),
+        (None, _) => WORKFLOW_OUTPUTS.iter().find_map(|name| outputs.get(name)),
+    };
+    match value.unwrap_or(outputs) {
+        JsonValue::Null => String::new(),
+        JsonValue::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Cuts a streamed answer at the first stop sequence.
+/// The text which may be the beginning of a stop sequence is held back until the next chunk.
+#[derive(Debug, Default, Clone)]
+pub struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+    stopped: bool,
+}
+
+impl StopMatcher {
+    /// Creates a matcher of the stop sequences, the empty ones are ignored.
+    pub fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Pushes a chunk of the answer, returns the text which can be sent.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(text);
+        let found = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(end) = found {
+            self.stopped = true;
+            self.pending.truncate(end);
+            return std::mem::take(&mut self.pending);
+        }
+        // the longest end of the text which is the beginning of a stop sequence
+        let held = self
+            .stops
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .filter(|&len| stop.is_char_boundary(len))
+                    .find(|&len| self.pending.ends_with(&stop[..len]))
+            })
+            .max()
+            .unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// Ends the answer, returns the text held back.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Returns whether a stop sequence was found.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+/// Counts the tokens of a streamed answer, approximating the BPE tokenizers:
+/// a token per 4 ASCII letters or digits of a word, per other non-space character, and per line.
+#[derive(Debug, Default, Clone)]
+pub struct TokenCounter {
+    word_len: usize,
+    tokens: u64,
+}
+
+impl TokenCounter {
+    /// Returns the number of tokens a character adds.
+    fn cost(&mut self, c: char) -> u64 {
+        if c.is_ascii_alphanumeric() {
+            self.word_len += 1;
+            return u64::from(self.word_len % 4 == 1);
+        }
+        self.word_len = 0;
+        match c {
+            '\n' => 1,
+            c if c.is_whitespace() => 0,
+            _ => 1,
+        }
+    }
+
+    /// Counts the tokens of a piece of the answer, returns the length of the text
+    /// within `max` tokens, if it is exceeded.
+    fn push(&mut self, text: &str, max: Option<u64>) -> Option<usize> {
+        for (i, c) in text.char_indices() {
+            let tokens = self.tokens + self.cost(c);
+            if max.is_some_and(|max| tokens > max) {
+                return Some(i);
+            }
+            self.tokens = tokens;
+        }
+        None
+    }
+}
+
+/// Cuts a streamed answer at its stop sequences and maximum number of tokens, which Dify
+/// doesn't support, and stops the Dify task once the rest of the answer is not needed.
+#[derive(Debug, Default, Clone)]
+pub struct AnswerLimits {
+    stop: StopMatcher,
+    max_tokens: Option<u64>,
+    counter: TokenCounter,
+    /// The estimated tokens of the prompt, for the usage of a cut answer.
+    prompt_tokens: u64,
+    /// The answer was cut, the rest is dropped.
+    cut: bool,
+}
+
+impl AnswerLimits {
+    /// Creates the limits of an answer, the empty stop sequences are ignored.
+    pub fn new(stops: Vec<String>, max_tokens: Option<u64>) -> Self {
+        Self {
+            stop: StopMatcher::new(stops),
+            max_tokens,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the prompt of the answer, the query and inputs sent to Dify.
+    /// Its tokens are estimated for the usage of a cut answer, which Dify doesn't report.
+    pub fn with_prompt<'a>(mut self, parts: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut counter = TokenCounter::default();
+        for part in parts {
+            counter.push(part, None);
+            counter.push("\n", None);
+        }
+        self.prompt_tokens = counter.tokens;
+        self
+    }
+
+    /// Returns whether the answer may be cut.
+    pub fn is_active(&self) -> bool {
+        !self.stop.stops.is_empty() || self.max_tokens.is_some()
+    }
+
+    /// Pushes a piece of the answer, returns the text to send and whether the token limit cut it.
+    fn push(&mut self, text: &str, end: bool) -> (String, bool) {
+        if self.cut {
+            return (String::new(), false);
+        }
+        let mut text = self.stop.push(text);
+        if end {
+            text.push_str(&self.stop.finish());
+        }
+        self.cut = self.stop.is_stopped();
+        match self.counter.push(&text, self.max_tokens) {
+            Some(len) => {
+                text.truncate(len);
+                self.cut = true;
+                (text, true)
+            }
+            None => (text, false),
+        }
+    }
+
+    /// Applies the limits to the events of an answer.
+    /// `stop_task` is called with the Dify task id when the answer is cut, to stop the generation.
+    pub fn limit(
+        mut self,
+        events: impl Stream<Item = AnswerEvent>,
+        stop_task: impl FnOnce(String),
+    ) -> impl Stream<Item = AnswerEvent> {
+        let mut task_id = String::new();
+        let mut stop_task = Some(stop_task);
+        let mut finished = false;
+        // the end of the stream is marked by `None`
+        let events = events.map(Some).chain(stream::once(async { None }));
+        events.flat_map(move |event| {
+            let mut events = Vec::new();
+            let (text, truncated) = match event {
+                Some(AnswerEvent::Started {
+                    task_id: ref id, ..
+                }) => {
+                    task_id.clone_from(id);
+                    events.extend(event);
+                    (String::new(), false)
+                }
+                Some(AnswerEvent::Text(text)) => self.push(&text, false),
+                Some(AnswerEvent::Finished(usage)) => {
+                    finished = true;
+                    let (text, truncated) = self.push("", true);
+                    if !text.is_empty() {
+                        events.push(AnswerEvent::Text(text));
+                    }
+                    if truncated {
+                        events.push(AnswerEvent::Truncated);
+                    }
+                    events.push(AnswerEvent::Finished(usage));
+                    return stream::iter(events);
+                }
+                // the rest of a cut answer is dropped, up to the end of the stopped task
+                Some(
+                    AnswerEvent::Reasoning(_) | AnswerEvent::Replaced(_) | AnswerEvent::Error(_),
+                ) if self.cut => (String::new(), false),
+                Some(event) => {
+                    events.push(event);
+                    (String::new(), false)
+                }
+                None if !finished => {
+                    // the text held back is sent, even without an end event
+                    let (text, truncated) = self.push("", true);
+                    if !text.is_empty() {
+                        events.push(AnswerEvent::Text(text));
+                    }
+                    if truncated {
+                        events.push(AnswerEvent::Truncated);
+                    }
+                    if self.cut {
+                        // the stopped task may not report its usage, it is estimated
+                        let completion_tokens = self.counter.tokens;
+                        events.push(AnswerEvent::Finished(TokenUsage {
+                            prompt_tokens: self.prompt_tokens,
+                            completion_tokens,
+                            total_tokens: self.prompt_tokens + completion_tokens,
+                        }));
+                    }
+                    return stream::iter(events);
+                }
+                None => (String::new(), false),
+            };
+            if !text.is_empty() {
+                events.push(AnswerEvent::Text(text));
+            }
+            if truncated {
+                events.push(AnswerEvent::Truncated);
+            }
+            if self.cut && !finished {
+                if let Some(stop_task) = stop_task.take() {
+                    stop_task(task_id.clone());
+                }
+            }
+            stream::iter(events)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn started() -> AnswerEvent {
+        AnswerEvent::Started {
+            id: "msg-1".into(),
+            created: 1,
+            conversation_id: None,
+            task_id: "task-1".into(),
+        }
+    }
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    /// Limits the answer events, returns the limited events and the stopped task.
+    fn limit(limits: AnswerLimits, events: Vec<AnswerEvent>) -> (Vec<AnswerEvent>, Option<String>) {
+        let stopped = Rc::new(RefCell::new(None));
+        let stop = {
+            let stopped = Rc::clone(&stopped);
+            move |task_id| *stopped.borrow_mut() = Some(task_id)
+        };
+        let events = futures::executor::block_on(
+            limits.limit(stream::iter(events), stop).collect::<Vec<_>>(),
+        );
+        let stopped = stopped.borrow_mut().take();
+        (events, stopped)
+    }
+
+    fn text(events: &[AnswerEvent]) -> String {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                AnswerEvent::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn finished(events: &[AnswerEvent]) -> Option<TokenUsage> {
+        events.iter().find_map(|event| match event {
+            AnswerEvent::Finished(usage) => Some(*usage),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn stop_split_across_chunks() {
+        let mut matcher = StopMatcher::new(vec!["END".into()]);
+        assert_eq!(matcher.push("hello E"), "hello ");
+        assert_eq!(matcher.push("N"), "");
+        assert_eq!(matcher.push("D world"), "");
+        assert!(matcher.is_stopped());
+        assert_eq!(matcher.push("more"), "");
+    }
+
+    #[test]
+    fn held_back_text_is_released() {
+        let mut matcher = StopMatcher::new(vec!["END".into(), String::new()]);
+        assert_eq!(matcher.push("hello EN"), "hello ");
+        assert_eq!(matcher.push("J"), "ENJ");
+        assert_eq!(matcher.push("E"), "");
+        assert_eq!(matcher.finish(), "E");
+        assert!(!matcher.is_stopped());
+    }
+
+    #[test]
+    fn stop_at_chunk_boundary() {
+        let mut matcher = StopMatcher::new(vec!["\n\n".into(), "STOP".into()]);
+        assert_eq!(matcher.push("one"), "one");
+        assert_eq!(matcher.push("STOP"), "");
+        assert!(matcher.is_stopped());
+
+        let limits = AnswerLimits::new(vec!["STOP".into()], None);
+        let events = vec![
+            started(),
+            AnswerEvent::Text("one ".into()),
+            AnswerEvent::Text("STOP".into()),
+            AnswerEvent::Text(" two".into()),
+            AnswerEvent::Finished(usage(3, 5)),
+        ];
+        let (events, stopped) = limit(limits, events);
+        assert_eq!(text(&events), "one ");
+        assert_eq!(stopped.as_deref(), Some("task-1"));
+        assert!(!events.iter().any(|e| matches!(e, AnswerEvent::Truncated)));
+        // the usage of Dify is kept when the stopped task reports it
+        assert_eq!(finished(&events).unwrap().total_tokens, 8);
+    }
+
+    #[test]
+    fn token_counter() {
+        let mut counter = TokenCounter::default();
+        assert_eq!(counter.push("hello world, ok\n", None), None);
+        // hel|lo wor|ld , ok \n
+        assert_eq!(counter.tokens, 7);
+        let mut counter = TokenCounter::default();
+        assert_eq!(counter.push("abcdefgh ij", Some(2)), Some(9));
+    }
+
+    #[test]
+    fn max_tokens_cut() {
+        let limits = AnswerLimits::new(vec![], Some(3));
+        let events = vec![
+            started(),
+            AnswerEvent::Text("abcd".into()),
+            AnswerEvent::Text("efgh ijkl".into()),
+            AnswerEvent::Text("mnop".into()),
+            AnswerEvent::Finished(usage(10, 20)),
+        ];
+        let (events, stopped) = limit(limits, events);
+        assert_eq!(text(&events), "abcdefgh ijkl");
+        assert_eq!(stopped.as_deref(), Some("task-1"));
+        let truncated = events
+            .iter()
+            .filter(|e| matches!(e, AnswerEvent::Truncated))
+            .count();
+        assert_eq!(truncated, 1);
+        assert_eq!(finished(&events).unwrap().completion_tokens, 20);
+    }
+
+    #[test]
+    fn cut_without_end_event() {
+        let limits = AnswerLimits::new(vec![], Some(2)).with_prompt(["tell me", "a story"]);
+        let events = vec![
+            started(),
+            AnswerEvent::Text("once upon a time".into()),
+            AnswerEvent::Reasoning("dropped".into()),
+        ];
+        let (events, stopped) = limit(limits, events);
+        assert_eq!(text(&events), "once upon ");
+        assert_eq!(stopped.as_deref(), Some("task-1"));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, AnswerEvent::Reasoning(_))));
+        // the usage is estimated, with the prompt tokens
+        let usage = finished(&events).unwrap();
+        assert_eq!(usage.prompt_tokens, 7);
+        assert_eq!(usage.completion_tokens, 2);
+        assert_eq!(usage.total_tokens, 9);
+    }
+
+    #[test]
+    fn uncut_answer_without_end_event() {
+        let limits = AnswerLimits::new(vec!["STOP".into()], Some(100));
+        let events = vec![started(), AnswerEvent::Text("ST".into())];
+        let (events, stopped) = limit(limits, events);
+        // the held back text is sent, and no usage is made up
+        assert_eq!(text(&events), "ST");
+        assert!(finished(&events).is_none());
+        assert!(stopped.is_none());
+    }
+}
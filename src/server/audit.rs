@@ -0,0 +1,517 @@
+//! Records the chat completions to an audit log, a JSONL file rotated by size.
+//!
+//! Each line holds the request of the client, the Dify request derived from it and the final
+//! response, a stream being reassembled into a chat completion. The `replay` command sends the
+//! recorded requests again to compare the answers, e.g. after a change of the Dify app.
+//! The records are never redacted, whatever `logging.redact_content`, as they must be replayable.
+use super::{access_log::AccessLog, helper::ApiError};
+use crate::config::AuditConfig;
+use axum::{
+    body::{self, Body},
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::{json, Value as JsonValue};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    thread,
+    time::SystemTime,
+};
+
+static AUDIT_LOG: OnceLock<AuditLog> = OnceLock::new();
+
+tokio::task_local! {
+    /// The Dify request of the audited chat completion.
+    static DIFY_REQUEST: Arc<Mutex<Option<JsonValue>>>;
+}
+
+/// Opens the audit log, if configured. The request bodies are buffered up to `max_body_size`.
+/// The records are written by a thread of their own, so a slow disk doesn't stall the requests.
+pub fn init_audit_log(config: &AuditConfig, max_body_size: usize) -> anyhow::Result<()> {
+    let Some(path) = config.path.clone() else {
+        return Ok(());
+    };
+    let mut writer = AuditWriter {
+        file: AuditFile::open(&path)?,
+        path,
+        max_size: config.max_size,
+        max_files: config.max_files,
+    };
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    thread::Builder::new()
+        .name("audit-log".into())
+        .spawn(move || {
+            for line in receiver {
+                writer.write(&line);
+            }
+        })?;
+    let audit_log = AuditLog {
+        max_body_size,
+        sender: Mutex::new(sender),
+    };
+    let _ = AUDIT_LOG.set(audit_log);
+    Ok(())
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+impl AuditFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+/// Writes the lines of the audit log, rotating its file.
+struct AuditWriter {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: AuditFile,
+}
+
+impl AuditWriter {
+    /// Appends a line, rotating the file first if it would exceed its size.
+    fn write(&mut self, line: &[u8]) {
+        if self.file.size > 0 && self.file.size + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(rotated) => self.file = rotated,
+                Err(e) => log::warn!("Failed to rotate {}: {}", self.path.display(), e),
+            }
+        }
+        match self.file.file.write_all(line) {
+            Ok(()) => self.file.size += line.len() as u64,
+            Err(e) => log::warn!("Failed to write {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Shifts the rotated files, dropping the oldest one, and opens a new file.
+    /// Without rotated files to keep, the file is deleted instead.
+    fn rotate(&self) -> io::Result<AuditFile> {
+        let rotated = |i: usize| PathBuf::from(format!("{}.{i}", self.path.display()));
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+            return AuditFile::open(&self.path);
+        }
+        for i in (1..self.max_files).rev() {
+            match fs::rename(rotated(i), rotated(i + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        AuditFile::open(&self.path)
+    }
+}
+
+struct AuditLog {
+    max_body_size: usize,
+    /// Sends the lines to the writer thread.
+    sender: Mutex<mpsc::Sender<Vec<u8>>>,
+}
+
+impl AuditLog {
+    /// Encodes a record and queues it for writing.
+    fn append(&self, record: &impl Serialize) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("Failed to encode an audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if self.sender.lock().unwrap().send(line).is_err() {
+            log::warn!("Failed to write an audit record: the writer has stopped");
+        }
+    }
+}
+
+/// Records the Dify request of the current chat completion, if it is audited.
+/// The choices and the retries send the same request, only the first one is kept.
+pub fn dify_request(req: &impl Serialize) {
+    let _ = DIFY_REQUEST.try_with(|slot| {
+        let mut slot = slot.lock().unwrap();
+        if slot.is_none() {
+            *slot = serde_json::to_value(req).ok();
+        }
+    });
+}
+
+/// An audit log line.
+#[derive(Serialize)]
+struct Record {
+    time: String,
+    request_id: Option<String>,
+    key_id: Option<String>,
+    status: u16,
+    request: JsonValue,
+    dify_request: Option<JsonValue>,
+    response: JsonValue,
+}
+
+/// Completes the record of a request with its response, then appends it.
+struct PendingRecord {
+    access: Option<AccessLog>,
+    status: u16,
+    request: JsonValue,
+    dify_request: Arc<Mutex<Option<JsonValue>>>,
+}
+
+impl PendingRecord {
+    fn append(&self, audit_log: &AuditLog, response: JsonValue) {
+        let access = self.access.as_ref();
+        let record = Record {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            request_id: access.map(AccessLog::request_id),
+            key_id: access.and_then(AccessLog::key_id),
+            status: self.status,
+            request: self.request.clone(),
+            dify_request: self.dify_request.lock().unwrap().take(),
+            response,
+        };
+        audit_log.append(&record);
+    }
+}
+
+/// Appends the record of a stream once it ends, with the reassembled answer.
+struct PendingStream {
+    audit_log: &'static AuditLog,
+    record: PendingRecord,
+    assembler: StreamAssembler,
+}
+
+impl Drop for PendingStream {
+    fn drop(&mut self) {
+        let response = std::mem::take(&mut self.assembler).finish();
+        self.record.append(self.audit_log, response);
+    }
+}
+
+/// Records a chat completion to the audit log, if enabled.
+pub async fn record(req: Request, next: Next) -> Response {
+    let Some(audit_log) = AUDIT_LOG.get() else {
+        return next.run(req).await;
+    };
+    let (parts, req_body) = req.into_parts();
+    let bytes = match body::to_bytes(req_body, audit_log.max_body_size).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let message = format!("Failed to buffer the request body: {e}");
+            let err = ApiError {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+                ..ApiError::invalid_request(message, None)
+            };
+            return err.into_response();
+        }
+    };
+    let request = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|_| JsonValue::String(String::from_utf8_lossy(&bytes).into_owned()));
+    let access = parts.extensions.get::<AccessLog>().cloned();
+    let dify_request = Arc::default();
+    let req = Request::from_parts(parts, Body::from(bytes));
+    let response = DIFY_REQUEST
+        .scope(Arc::clone(&dify_request), next.run(req))
+        .await;
+
+    let record = PendingRecord {
+        access,
+        status: response.status().as_u16(),
+        request,
+        dify_request,
+    };
+    let is_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    let (parts, resp_body) = response.into_parts();
+    if is_stream {
+        let mut pending = PendingStream {
+            audit_log,
+            record,
+            assembler: StreamAssembler::default(),
+        };
+        let stream = resp_body.into_data_stream().map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                pending.assembler.feed(bytes);
+            }
+            chunk
+        });
+        return Response::from_parts(parts, Body::from_stream(stream));
+    }
+    let bytes = match body::to_bytes(resp_body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::warn!("Failed to buffer a response for the audit log: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let response = serde_json::from_slice(&bytes).unwrap_or(JsonValue::Null);
+    record.append(audit_log, response);
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// The answer of a choice, reassembled from its chunks.
+#[derive(Default)]
+struct AssembledChoice {
+    content: String,
+    reasoning_content: String,
+    tool_calls: BTreeMap<u64, JsonValue>,
+    function_call: Option<JsonValue>,
+    finish_reason: JsonValue,
+}
+
+/// Reassembles a streamed chat completion from its SSE chunks, as a `chat.completion`.
+#[derive(Default)]
+pub struct StreamAssembler {
+    /// The bytes of the line being received.
+    buffer: Vec<u8>,
+    id: JsonValue,
+    created: JsonValue,
+    model: JsonValue,
+    choices: BTreeMap<u64, AssembledChoice>,
+    usage: JsonValue,
+    error: Option<JsonValue>,
+}
+
+impl StreamAssembler {
+    /// Reads a piece of the SSE stream.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            if let Ok(chunk) = serde_json::from_str(data.trim()) {
+                self.chunk(chunk);
+            }
+        }
+    }
+
+    fn chunk(&mut self, chunk: JsonValue) {
+        if let Some(error) = chunk.get("error") {
+            self.error = Some(error.clone());
+            return;
+        }
+        for (field, value) in [
+            (&mut self.id, &chunk["id"]),
+            (&mut self.created, &chunk["created"]),
+            (&mut self.model, &chunk["model"]),
+            (&mut self.usage, &chunk["usage"]),
+        ] {
+            if !value.is_null() {
+                *field = value.clone();
+            }
+        }
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            let index = choice["index"].as_u64().unwrap_or_default();
+            let assembled = self.choices.entry(index).or_default();
+            let delta = &choice["delta"];
+            if let Some(content) = delta["content"].as_str() {
+                assembled.content.push_str(content);
+            }
+            if let Some(reasoning) = delta["reasoning_content"].as_str() {
+                assembled.reasoning_content.push_str(reasoning);
+            }
+            for call in delta["tool_calls"].as_array().into_iter().flatten() {
+                let index = call["index"].as_u64().unwrap_or_default();
+                let assembled = assembled.tool_calls.entry(index).or_insert_with(|| {
+                    json!({"id": "", "type": "function", "function": {"name": "", "arguments": ""}})
+                });
+                merge_call(&mut assembled["function"], &call["function"]);
+                if let Some(id) = call["id"].as_str() {
+                    assembled["id"] = id.into();
+                }
+            }
+            if delta["function_call"].is_object() {
+                let assembled = assembled
+                    .function_call
+                    .get_or_insert_with(|| json!({"name": "", "arguments": ""}));
+                merge_call(assembled, &delta["function_call"]);
+            }
+            if !choice["finish_reason"].is_null() {
+                assembled.finish_reason = choice["finish_reason"].clone();
+            }
+        }
+    }
+
+    /// Returns the reassembled chat completion, with the error ending the stream if any.
+    pub fn finish(self) -> JsonValue {
+        let choices = self
+            .choices
+            .into_iter()
+            .map(|(index, choice)| {
+                let mut message = json!({"role": "assistant", "content": choice.content});
+                if !choice.reasoning_content.is_empty() {
+                    message["reasoning_content"] = choice.reasoning_content.into();
+                }
+                if !choice.tool_calls.is_empty() {
+                    message["tool_calls"] = choice.tool_calls.into_values().collect();
+                }
+                if let Some(function_call) = choice.function_call {
+                    message["function_call"] = function_call;
+                }
+                json!({"index": index, "message": message, "finish_reason": choice.finish_reason})
+            })
+            .collect::<Vec<_>>();
+        let mut completion = json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+            "usage": self.usage,
+        });
+        if let Some(error) = self.error {
+            completion["error"] = error;
+        }
+        completion
+    }
+}
+
+/// Merges the delta of a function call: the name is set, the arguments are appended.
+fn merge_call(call: &mut JsonValue, delta: &JsonValue) {
+    if let Some(name) = delta["name"].as_str() {
+        call["name"] = name.into();
+    }
+    if let Some(arguments) = delta["arguments"].as_str() {
+        let mut merged = call["arguments"].as_str().unwrap_or_default().to_owned();
+        merged.push_str(arguments);
+        call["arguments"] = merged.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends records of 11 bytes to a log rotated at 25 bytes, returns the files of the log.
+    fn rotate(max_files: usize, records: usize) -> Vec<String> {
+        let dir =
+            std::env::temp_dir().join(format!("audit-test-{}-{max_files}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let mut writer = AuditWriter {
+            file: AuditFile::open(&path).unwrap(),
+            path,
+            max_size: 25,
+            max_files,
+        };
+        for i in 0..records {
+            writer.write(format!("\"record-{i}\"\n").as_bytes());
+        }
+        let mut files = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let text = fs::read_to_string(entry.path()).unwrap();
+                format!("{}: {}", entry.file_name().to_string_lossy(), text.trim())
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        files
+    }
+
+    #[test]
+    fn rotation_keeps_max_files() {
+        assert_eq!(
+            rotate(2, 7),
+            [
+                "audit.jsonl.1: \"record-4\"\n\"record-5\"",
+                "audit.jsonl.2: \"record-2\"\n\"record-3\"",
+                "audit.jsonl: \"record-6\"",
+            ]
+        );
+        assert_eq!(rotate(0, 3), ["audit.jsonl: \"record-2\""]);
+    }
+
+    fn sse(chunk: JsonValue) -> String {
+        format!("data: {chunk}\n\n")
+    }
+
+    #[test]
+    fn assembler_reads_lines_split_across_chunks() {
+        let mut assembler = StreamAssembler::default();
+        let stream = [
+            sse(json!({"id": "chatcmpl-1", "created": 1, "model": "dify",
+                "choices": [{"index": 0, "delta": {"role": "assistant", "content": "Hel"}}]})),
+            sse(json!({"choices": [{"index": 0, "delta": {"content": "lo, wörld"}}]})),
+            sse(
+                json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}],
+                "usage": {"total_tokens": 3}}),
+            ),
+            "data: [DONE]\n\n".to_string(),
+        ]
+        .concat();
+        // the bytes are split within the lines and within a multibyte character
+        let split = stream.find('ö').unwrap() + 1;
+        for bytes in [&stream.as_bytes()[..7], &stream.as_bytes()[7..split]] {
+            assembler.feed(bytes);
+        }
+        for bytes in stream.as_bytes()[split..].chunks(5) {
+            assembler.feed(bytes);
+        }
+        let completion = assembler.finish();
+        assert_eq!(completion["id"], "chatcmpl-1");
+        assert_eq!(completion["object"], "chat.completion");
+        assert_eq!(completion["usage"]["total_tokens"], 3);
+        let choice = &completion["choices"][0];
+        assert_eq!(choice["message"]["content"], "Hello, wörld");
+        assert_eq!(choice["finish_reason"], "stop");
+        assert!(completion.get("error").is_none());
+    }
+
+    #[test]
+    fn assembler_merges_tool_calls() {
+        let mut assembler = StreamAssembler::default();
+        let call = |index: u64, function: JsonValue| json!({"choices": [{"index": 1, "delta": {"tool_calls": [{"index": index, "function": function}]}}]});
+        let mut first = call(0, json!({"name": "search", "arguments": "{\"q\":"}));
+        first["choices"][0]["delta"]["tool_calls"][0]["id"] = "call_1".into();
+        for chunk in [
+            first,
+            call(1, json!({"name": "weather", "arguments": "{}"})),
+            call(0, json!({"arguments": " \"rust\"}"})),
+            json!({"choices": [{"index": 1, "delta": {}, "finish_reason": "tool_calls"}]}),
+        ] {
+            assembler.feed(sse(chunk).as_bytes());
+        }
+        let completion = assembler.finish();
+        let choice = &completion["choices"][0];
+        assert_eq!(choice["index"], 1);
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        let calls = &choice["message"]["tool_calls"];
+        assert_eq!(calls[0]["id"], "call_1");
+        assert_eq!(calls[0]["function"]["name"], "search");
+        assert_eq!(calls[0]["function"]["arguments"], "{\"q\": \"rust\"}");
+        assert_eq!(calls[1]["function"]["name"], "weather");
+        assert_eq!(calls[1]["function"]["arguments"], "{}");
+    }
+
+    #[test]
+    fn assembler_keeps_stream_error() {
+        let mut assembler = StreamAssembler::default();
+        assembler
+            .feed(sse(json!({"choices": [{"index": 0, "delta": {"content": "Hi"}}]})).as_bytes());
+        assembler.feed(sse(json!({"error": {"code": "upstream_error"}})).as_bytes());
+        let completion = assembler.finish();
+        assert_eq!(completion["choices"][0]["message"]["content"], "Hi");
+        assert_eq!(completion["error"]["code"], "upstream_error");
+    }
+}
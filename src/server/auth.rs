@@ -0,0 +1,120 @@
+//! Authenticates the clients of the gateway.
+//!
+//! In the `strict` mode, the clients use keys issued by the gateway, stored as SHA-256 hashes in
+//! the config. A key may be restricted to some models, and mapped to their Dify app keys.
+//! The `passthrough` mode forwards the Bearer token of the client to Dify as the app key.
+use super::helper::ApiError;
+use crate::config::{AuthMode, Config, KeyConfig};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The prefix of the keys generated by the gateway.
+const KEY_PREFIX: &str = "sk-gw-";
+
+/// Returns the SHA-256 hash of a key, in hexadecimal, as stored in the config.
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Generates a random key.
+pub fn generate_key() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 24];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow::anyhow!("failed to generate a key: {e}"))?;
+    let random = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    Ok(format!("{KEY_PREFIX}{random}"))
+}
+
+/// The keys issued by the gateway, by hash.
+#[derive(Default)]
+pub struct KeyStore {
+    keys: HashMap<String, (String, KeyConfig)>,
+}
+
+impl KeyStore {
+    /// Indexes the keys of a validated config.
+    pub fn new(config: &Config) -> Self {
+        let keys = config
+            .auth
+            .keys
+            .iter()
+            .map(|(name, key)| (key.hash.to_lowercase(), (name.clone(), key.clone())))
+            .collect();
+        Self { keys }
+    }
+
+    /// Authenticates a client by its Bearer token.
+    pub fn authenticate(&self, mode: AuthMode, token: Option<String>) -> Result<Client, ApiError> {
+        match mode {
+            AuthMode::Disabled => Ok(Client::Anonymous),
+            AuthMode::Passthrough => match token {
+                Some(token) => Ok(Client::Passthrough(token)),
+                None => Err(ApiError::unauthorized(MISSING_KEY)),
+            },
+            AuthMode::Strict => {
+                let token = token.ok_or_else(|| ApiError::unauthorized(MISSING_KEY))?;
+                let Some((name, key)) = self.keys.get(&hash_key(&token)) else {
+                    return Err(ApiError::unauthorized("Incorrect API key provided."));
+                };
+                if key.revoked {
+                    return Err(ApiError::unauthorized("The API key was revoked."));
+                }
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                if key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                    return Err(ApiError::unauthorized("The API key has expired."));
+                }
+                Ok(Client::Key {
+                    name: name.clone(),
+                    key: key.clone(),
+                })
+            }
+        }
+    }
+}
+
+const MISSING_KEY: &str =
+    "You didn't provide an API key, in an Authorization header as `Bearer YOUR_KEY`.";
+
+/// An authenticated client.
+pub enum Client {
+    /// A client with a key issued by the gateway, by name.
+    Key { name: String, key: KeyConfig },
+    /// A client whose Bearer token is forwarded to Dify.
+    Passthrough(String),
+    /// Any client, the authentication is disabled.
+    Anonymous,
+}
+
+impl Client {
+    /// Returns whether the client may use a model.
+    pub fn allows(&self, model: &str) -> bool {
+        match self {
+            Self::Key { key, .. } => key.allows(model),
+            Self::Passthrough(_) | Self::Anonymous => true,
+        }
+    }
+
+    /// Returns the Dify app key of a model for the client, if it isn't the configured one.
+    pub fn token(&self, model: &str) -> Option<String> {
+        match self {
+            Self::Key { key, .. } => key.api_keys.get(model).cloned(),
+            Self::Passthrough(token) => Some(token.clone()),
+            Self::Anonymous => None,
+        }
+    }
+
+    /// Identifies the client, to scope its conversations and tasks.
+    pub fn id(&self) -> Option<String> {
+        match self {
+            Self::Key { name, .. } => Some(format!("key:{name}")),
+            Self::Passthrough(token) => Some(hash_key(token)),
+            Self::Anonymous => None,
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Maps the OpenAI conversations onto the Dify conversations.
+//!
+//! OpenAI clients resend the whole conversation on every turn, while Dify keeps the history
+//! on its side by `conversation_id`. Once a turn is answered, the fingerprint of the messages
+//! including the answer is mapped to the Dify conversation, so the next turn, which starts with
+//! the same messages, is recognized and only its new messages are sent upstream.
+use crate::config::ConversationsConfig;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The fingerprint of a conversation prefix, built message by message.
+#[derive(Clone)]
+pub struct Fingerprint(DefaultHasher);
+
+impl Fingerprint {
+    /// Starts a fingerprint, scoped to the model, the user and the API key.
+    pub fn new(scope: &[&str]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        scope.hash(&mut hasher);
+        Self(hasher)
+    }
+
+    /// Adds a message, surrounding whitespace is ignored as clients often trim the answers.
+    pub fn push(&mut self, role: &str, content: &str) {
+        role.hash(&mut self.0);
+        content.trim().hash(&mut self.0);
+    }
+
+    /// Returns the fingerprint of the messages added so far.
+    pub fn value(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+struct Entry {
+    conversation_id: String,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_fingerprint: HashMap<u64, Entry>,
+    /// The fingerprints ordered by expiry, for the eviction.
+    by_expiry: BTreeSet<(Instant, u64)>,
+}
+
+impl Entries {
+    /// Evicts the entry expiring first.
+    fn evict_first(&mut self) {
+        if let Some((_, fingerprint)) = self.by_expiry.pop_first() {
+            self.by_fingerprint.remove(&fingerprint);
+        }
+    }
+}
+
+/// The in-memory mapping of conversation fingerprints to Dify conversation ids.
+#[derive(Default)]
+pub struct ConversationStore {
+    entries: Mutex<Entries>,
+}
+
+impl ConversationStore {
+    /// Returns the Dify conversation id of a fingerprint, if known and not expired.
+    pub fn get(&self, fingerprint: u64) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .by_fingerprint
+            .get(&fingerprint)
+            .filter(|entry| entry.expires_at > Instant::now())
+            .map(|entry| entry.conversation_id.clone())
+    }
+
+    /// Maps a fingerprint to a Dify conversation id.
+    /// The expired entries are evicted, then the ones expiring first while the store is full.
+    pub fn insert(&self, fingerprint: u64, conversation_id: String, config: &ConversationsConfig) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.by_fingerprint.remove(&fingerprint) {
+            entries.by_expiry.remove(&(entry.expires_at, fingerprint));
+        }
+        while entries
+            .by_expiry
+            .first()
+            .is_some_and(|(expires_at, _)| *expires_at <= now)
+        {
+            entries.evict_first();
+        }
+        while !entries.by_expiry.is_empty() && entries.by_expiry.len() >= config.max_entries {
+            entries.evict_first();
+        }
+        let expires_at = now + Duration::from_secs(config.ttl);
+        entries.by_expiry.insert((expires_at, fingerprint));
+        let entry = Entry {
+            conversation_id,
+            expires_at,
+        };
+        entries.by_fingerprint.insert(fingerprint, entry);
+    }
+}
+
+/// Remembers the Dify conversation of a chat, once its answer is known.
+pub struct ConversationRecorder {
+    store: Arc<ConversationStore>,
+    fingerprint: Fingerprint,
+    config: ConversationsConfig,
+}
+
+impl ConversationRecorder {
+    /// Creates a recorder, `fingerprint` covers all the messages of the request.
+    pub fn new(
+        store: Arc<ConversationStore>,
+        fingerprint: Fingerprint,
+        config: ConversationsConfig,
+    ) -> Self {
+        Self {
+            store,
+            fingerprint,
+            config,
+        }
+    }
+
+    /// Maps the messages followed by the answer to the Dify conversation.
+    pub fn record(&self, answer: &str, conversation_id: &str) {
+        if conversation_id.is_empty() {
+            return;
+        }
+        let mut fingerprint = self.fingerprint.clone();
+        fingerprint.push("assistant", answer);
+        self.store.insert(
+            fingerprint.value(),
+            conversation_id.to_owned(),
+            &self.config,
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Dify APIs which are not (or not loosely enough) covered by `dify_client`.
+//!
+//! The typed responses of `dify_client` are strict, so newer Dify releases adding fields or
+//! variants fail to deserialize. Metadata endpoints are fetched here as plain JSON instead.
+//! The streaming requests of `dify_client` don't check the HTTP status, so an upstream error
+//! ends up as an empty stream, and fail on the events it doesn't know, like the workflow
+//! `text_chunk`. They are sent from here as well, with loosely typed events.
+use super::{access_log::Content, answer::TokenUsage, audit, telemetry};
+use anyhow::{anyhow, Result as AnyResult};
+use dify_client::{
+    request::{ChatMessagesRequest, CompletionMessagesRequest, ResponseMode, WorkflowsRunRequest},
+    response::{AppMode, ErrorResponse},
+    Client as DifyClient,
+};
+use eventsource_stream::Eventsource;
+use futures::{Stream, StreamExt};
+use opentelemetry::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::sync::OnceLock;
+
+/// Returns the shared HTTP client used for the raw Dify requests.
+fn http_client() -> &'static reqwest::Client {
+    static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    HTTP_CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// Basic information of a Dify app, returned by `GET /v1/info`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AppInfo {
+    /// The app name.
+    #[serde(default)]
+    pub name: String,
+    /// The app description.
+    #[serde(default)]
+    pub description: String,
+    /// The app tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Sends a request to a Dify API, returning the upstream error if the response status is not a success.
+/// The `token` overrides the API key configured on the client.
+/// The call is traced in the span of `cx`, whose trace context is sent along.
+async fn send(
+    client: &DifyClient,
+    token: Option<&str>,
+    builder: reqwest::RequestBuilder,
+    cx: &Context,
+) -> AnyResult<reqwest::Response> {
+    let config = &client.config;
+    let mut builder = builder
+        .bearer_auth(token.unwrap_or(config.api_key.as_str()))
+        .headers(telemetry::headers(cx));
+    if !config.timeout.is_zero() {
+        builder = builder.timeout(config.timeout);
+    }
+    let result = try_send(builder).await;
+    match &result {
+        Ok(resp) => telemetry::record_status(cx, resp.status().as_u16()),
+        Err(e) => telemetry::record_failure(cx, e),
+    }
+    result
+}
+
+async fn try_send(builder: reqwest::RequestBuilder) -> AnyResult<reqwest::Response> {
+    let resp = builder.send().await?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let text = resp.text().await?;
+    match serde_json::from_str::<ErrorResponse>(&text) {
+        Ok(err) => Err(anyhow!(err)),
+        Err(_) => Err(anyhow!(ErrorResponse {
+            code: "unknown_error".into(),
+            message: text,
+            status: status.as_u16().into(),
+        })),
+    }
+}
+
+/// Returns the path of a Dify API call without its task id, to name its span.
+fn route(path: &str) -> String {
+    match path
+        .strip_suffix("/stop")
+        .and_then(|path| path.rsplit_once('/'))
+    {
+        Some((prefix, _)) => format!("{prefix}/:task_id/stop"),
+        None => path.to_owned(),
+    }
+}
+
+/// Sends a GET request to a Dify API path and returns the JSON body.
+async fn get_json(client: &DifyClient, token: Option<&str>, path: &str) -> AnyResult<JsonValue> {
+    let builder = http_client()
+        .get(format!("{}{}", client.config.base_url, path))
+        .query(&[("user", "dify-openai-apis")]);
+    let cx = telemetry::dify_span("GET", path);
+    let text = send(client, token, builder, &cx).await?.text().await?;
+    serde_json::from_str(&text).map_err(|e| anyhow!("invalid Dify response: {e}"))
+}
+
+/// Fetches the basic information of the app, `GET /v1/info`.
+pub async fn app_info(client: &DifyClient, token: Option<&str>) -> AnyResult<AppInfo> {
+    let info = get_json(client, token, "/v1/info").await?;
+    Ok(serde_json::from_value(info)?)
+}
+
+/// Fetches the app parameters (input form, file upload, ...), `GET /v1/parameters`.
+pub async fn app_parameters(client: &DifyClient, token: Option<&str>) -> AnyResult<JsonValue> {
+    get_json(client, token, "/v1/parameters").await
+}
+
+/// The blocking response of a completion message.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CompletionMessage {
+    pub message_id: String,
+    pub task_id: String,
+    pub created_at: u64,
+    pub answer: String,
+    pub metadata: JsonValue,
+}
+
+/// A piece of a chat message, the `message` and `agent_message` events.
+/// The `message_replace` event of the content moderation has the same fields, with the whole reply.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct MessageChunk {
+    pub message_id: String,
+    pub task_id: String,
+    pub conversation_id: Option<String>,
+    pub created_at: u64,
+    pub answer: String,
+}
+
+/// A thought of an agent, the `agent_thought` event.
+/// It is sent again as the thought goes on, e.g. with the observation of its tool.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct AgentThought {
+    pub id: String,
+    pub message_id: String,
+    pub task_id: String,
+    pub conversation_id: Option<String>,
+    pub created_at: u64,
+    pub thought: String,
+    pub observation: String,
+    pub tool: String,
+    pub tool_input: String,
+}
+
+/// The end of a chat message, the `message_end` event.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct MessageEnd {
+    pub message_id: String,
+    pub task_id: String,
+    pub conversation_id: Option<String>,
+    pub created_at: u64,
+    pub metadata: JsonValue,
+}
+
+/// The start of a workflow run, the `workflow_started` event.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct WorkflowStarted {
+    pub task_id: String,
+    pub workflow_run_id: String,
+    pub data: WorkflowRun,
+}
+
+/// A workflow node event, `node_started` or `node_finished`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NodeEvent {
+    pub task_id: String,
+    pub data: NodeData,
+}
+
+/// The node of a workflow node event.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NodeData {
+    pub node_id: String,
+    pub node_type: String,
+    pub title: String,
+    pub status: Option<String>,
+}
+
+/// A piece of the workflow output, the `text_chunk` event.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TextChunk {
+    pub task_id: String,
+    pub data: TextChunkData,
+}
+
+/// The text of a `text_chunk` event.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TextChunkData {
+    pub text: String,
+}
+
+/// A workflow run, of the `workflow_finished` event and the blocking response.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct WorkflowRun {
+    pub id: String,
+    /// `running`, `succeeded`, `failed` or `stopped`.
+    pub status: String,
+    pub outputs: JsonValue,
+    pub error: Option<String>,
+    pub total_tokens: u64,
+    pub created_at: u64,
+}
+
+/// The end of a workflow run, the `workflow_finished` event.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct WorkflowFinished {
+    pub task_id: String,
+    pub workflow_run_id: String,
+    pub data: WorkflowRun,
+}
+
+/// The blocking response of a workflow run.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct WorkflowRunResponse {
+    pub task_id: String,
+    pub workflow_run_id: String,
+    pub data: WorkflowRun,
+}
+
+/// An event of a Dify streaming response.
+/// The events the gateway doesn't use are tolerated as `Other`.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StreamEvent {
+    Message(MessageChunk),
+    AgentMessage(MessageChunk),
+    MessageReplace(MessageChunk),
+    AgentThought(AgentThought),
+    MessageEnd(MessageEnd),
+    WorkflowStarted(WorkflowStarted),
+    NodeStarted(NodeEvent),
+    NodeFinished(NodeEvent),
+    TextChunk(TextChunk),
+    WorkflowFinished(WorkflowFinished),
+    Error(ErrorResponse),
+    Ping,
+    #[serde(other)]
+    Other,
+}
+
+/// Sends a POST request to a Dify API path and returns the JSON body.
+async fn post_json<T: DeserializeOwned>(
+    client: &DifyClient,
+    token: Option<&str>,
+    path: &str,
+    body: &impl Serialize,
+) -> AnyResult<T> {
+    let builder = http_client()
+        .post(format!("{}{}", client.config.base_url, path))
+        .json(body);
+    let cx = telemetry::dify_span("POST", &route(path));
+    let text = send(client, token, builder, &cx).await?.text().await?;
+    let json: JsonValue =
+        serde_json::from_str(&text).map_err(|e| anyhow!("invalid Dify response: {e}"))?;
+    record_answer(&cx, &json);
+    serde_json::from_value(json).map_err(|e| anyhow!("invalid Dify response: {e}"))
+}
+
+/// Records the conversation and the tokens of a blocking answer on the span of its call.
+fn record_answer(cx: &Context, answer: &JsonValue) {
+    if let Some(conversation_id) = answer["conversation_id"].as_str() {
+        telemetry::record_conversation(cx, conversation_id);
+    }
+    if answer["metadata"].is_object() {
+        telemetry::record_usage(cx, TokenUsage::from_metadata(&answer["metadata"]));
+    } else if let Some(total_tokens) = answer["data"]["total_tokens"].as_u64() {
+        telemetry::record_usage(cx, TokenUsage::from_total(total_tokens));
+    }
+    if answer["data"]["status"].as_str() == Some("failed") {
+        let error = answer["data"]["error"]
+            .as_str()
+            .unwrap_or("workflow failed");
+        telemetry::record_error(cx, "workflow_failed", Some(error));
+    }
+}
+
+/// Records the conversation, the tokens and the errors of a stream on the span of its call.
+fn record_event(cx: &Context, event: &StreamEvent) {
+    match event {
+        StreamEvent::MessageEnd(end) => {
+            if let Some(conversation_id) = end.conversation_id.as_deref() {
+                telemetry::record_conversation(cx, conversation_id);
+            }
+            telemetry::record_usage(cx, TokenUsage::from_metadata(&end.metadata));
+        }
+        StreamEvent::WorkflowFinished(finished) => {
+            telemetry::record_usage(cx, TokenUsage::from_total(finished.data.total_tokens));
+            if finished.data.status == "failed" {
+                let error = finished.data.error.as_deref().unwrap_or("workflow failed");
+                telemetry::record_error(cx, "workflow_failed", Some(error));
+            }
+        }
+        StreamEvent::Error(err) => telemetry::record_error(cx, &err.code, Some(&err.message)),
+        _ => {}
+    }
+}
+
+/// Sends a POST request to a Dify API path and returns the stream of the response events.
+async fn post_stream(
+    client: &DifyClient,
+    token: Option<&str>,
+    path: &str,
+    body: &impl Serialize,
+) -> AnyResult<impl Stream<Item = AnyResult<StreamEvent>>> {
+    let builder = http_client()
+        .post(format!("{}{}", client.config.base_url, path))
+        .json(body);
+    // the span ends with the stream
+    let cx = telemetry::dify_span("POST", path);
+    let resp = send(client, token, builder, &cx).await?;
+    let stream = resp.bytes_stream().eventsource().map(move |event| {
+        let event = event.map_err(|e| anyhow!("{e}"))?;
+        log::trace!("Dify event: {}", Content(&event.data));
+        let event =
+            serde_json::from_str(&event.data).map_err(|e| anyhow!("invalid Dify event: {e}"))?;
+        record_event(&cx, &event);
+        Ok(event)
+    });
+    Ok(stream)
+}
+
+/// Sends a chat message in streaming mode, `POST /v1/chat-messages`.
+pub async fn chat_messages_stream(
+    client: &DifyClient,
+    token: Option<&str>,
+    mut req_data: ChatMessagesRequest,
+) -> AnyResult<impl Stream<Item = AnyResult<StreamEvent>>> {
+    req_data.response_mode = ResponseMode::Streaming;
+    audit::dify_request(&req_data);
+    post_stream(client, token, "/v1/chat-messages", &req_data).await
+}
+
+/// Runs a workflow in blocking mode, `POST /v1/workflows/run`.
+pub async fn workflows_run(
+    client: &DifyClient,
+    token: Option<&str>,
+    mut req_data: WorkflowsRunRequest,
+) -> AnyResult<WorkflowRunResponse> {
+    req_data.response_mode = ResponseMode::Blocking;
+    audit::dify_request(&req_data);
+    post_json(client, token, "/v1/workflows/run", &req_data).await
+}
+
+/// Runs a workflow in streaming mode, `POST /v1/workflows/run`.
+pub async fn workflows_run_stream(
+    client: &DifyClient,
+    token: Option<&str>,
+    mut req_data: WorkflowsRunRequest,
+) -> AnyResult<impl Stream<Item = AnyResult<StreamEvent>>> {
+    req_data.response_mode = ResponseMode::Streaming;
+    audit::dify_request(&req_data);
+    post_stream(client, token, "/v1/workflows/run", &req_data).await
+}
+
+/// Sends a completion message in blocking mode, `POST /v1/completion-messages`.
+pub async fn completion_messages(
+    client: &DifyClient,
+    token: Option<&str>,
+    mut req_data: CompletionMessagesRequest,
+) -> AnyResult<CompletionMessage> {
+    req_data.response_mode = ResponseMode::Blocking;
+    audit::dify_request(&req_data);
+    post_json(client, token, "/v1/completion-messages", &req_data).await
+}
+
+/// Sends a completion message in streaming mode, `POST /v1/completion-messages`.
+pub async fn completion_messages_stream(
+    client: &DifyClient,
+    token: Option<&str>,
+    mut req_data: CompletionMessagesRequest,
+) -> AnyResult<impl Stream<Item = AnyResult<StreamEvent>>> {
+    req_data.response_mode = ResponseMode::Streaming;
+    audit::dify_request(&req_data);
+    post_stream(client, token, "/v1/completion-messages", &req_data).await
+}
+
+/// Stops a streamed generation, `POST /v1/chat-messages/:task_id/stop`,
+/// or the equivalent endpoint of the completion and workflow apps.
+pub async fn stop_task(
+    client: &DifyClient,
+    token: Option<&str>,
+    app_type: &AppMode,
+    task_id: &str,
+    user: &str,
+) -> AnyResult<()> {
+    let path = match app_type {
+        AppMode::Completion => format!("/v1/completion-messages/{task_id}/stop"),
+        AppMode::Workflow => format!("/v1/workflows/tasks/{task_id}/stop"),
+        _ => format!("/v1/chat-messages/{task_id}/stop"),
+    };
+    let body = serde_json::json!({ "user": user });
+    let _: JsonValue = post_json(client, token, &path, &body).await?;
+    Ok(())
+}
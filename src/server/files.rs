@@ -0,0 +1,45 @@
+//! Turns the images of the chat messages into Dify files.
+use super::{helper::ApiError, telemetry};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dify_client::{
+    api::Api,
+    request::{FileInput, FileType, FilesUploadRequest},
+};
+use opentelemetry::trace::FutureExt;
+
+/// Converts an image URL into a Dify file.
+/// HTTP(S) URLs are passed to Dify as remote files, `data:` URIs are uploaded with Dify's file upload API.
+pub async fn image_file(api: &Api<'_>, url: &str, user: &str) -> anyhow::Result<FileInput> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return Ok(FileInput::RemoteUrl {
+            type_: FileType::Image,
+            url: url.to_owned(),
+        });
+    }
+    let Some(data) = url.strip_prefix("data:") else {
+        let message = "Unsupported image URL, expected an HTTP(S) URL or a base64 `data:` URI.";
+        return Err(ApiError::invalid_request(message, Some("messages")).into());
+    };
+    let file = data
+        .split_once(";base64,")
+        .and_then(|(_, data)| STANDARD.decode(data.trim()).ok())
+        .ok_or_else(|| {
+            let message = "Invalid image `data:` URI, expected base64 encoded data.";
+            ApiError::invalid_request(message, Some("messages"))
+        })?;
+    let req_data = FilesUploadRequest {
+        file: file.into(),
+        user: user.to_owned(),
+    };
+    let cx = telemetry::dify_span("POST", "/v1/files/upload");
+    let resp = api.files_upload(req_data).with_context(cx.clone()).await;
+    // the upstream errors are reported as such, only the invalid images are the client's
+    let resp = resp.map_err(|e| {
+        telemetry::record_failure(&cx, &e);
+        e
+    })?;
+    Ok(FileInput::LocalFile {
+        type_: FileType::Image,
+        upload_file_id: resp.id,
+    })
+}
@@ -0,0 +1,301 @@
+//! Serves the health endpoints: `GET /healthz`, `GET /readyz` and `GET /status`.
+//!
+//! The readiness and the status probe each Dify app with its configured API key, fetching its
+//! parameters, so an unreachable upstream or a revoked key is reported before the clients hit it.
+//! The probes are cached for a while, the apps without an API key are not probed.
+//!
+//! The status requires a gateway key with `auth.mode = "strict"`, and only shows the models of
+//! the key. Without gateway keys, it leaves out the base URLs and the error messages of Dify.
+use super::{
+    access_log::AccessLog,
+    dify,
+    helper::{ApiError, AppState},
+    registry::ModelRoute,
+    v1_handlers::authenticate,
+};
+use crate::config::AuthMode;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use dify_client::response::ErrorResponse;
+use serde::Serialize;
+use serde_json::{json, Value as JsonValue};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+const PROBE_TTL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The last probe of an app.
+#[derive(Clone)]
+struct Probe {
+    /// When the last probe started.
+    checked_at: Instant,
+    reachable: bool,
+    latency_ms: u64,
+    last_success: Option<SystemTime>,
+    last_error: Option<(SystemTime, String)>,
+}
+
+impl Probe {
+    /// Returns whether the first probe of the app is still running.
+    fn is_pending(&self) -> bool {
+        self.last_success.is_none() && self.last_error.is_none()
+    }
+}
+
+/// Probes the Dify apps, by model, base URL and API key.
+/// A model probed with another upstream or key, after a config reload, starts over.
+pub struct UpstreamProbes {
+    started: Instant,
+    probes: Mutex<HashMap<(String, String, String), Probe>>,
+}
+
+impl Default for UpstreamProbes {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            probes: Default::default(),
+        }
+    }
+}
+
+fn probe_key(route: &ModelRoute) -> (String, String, String) {
+    let config = &route.client.config;
+    (
+        route.name.clone(),
+        config.base_url.clone(),
+        config.api_key.clone(),
+    )
+}
+
+/// Describes the failure of a probe.
+fn probe_error(err: &anyhow::Error) -> String {
+    match err.downcast_ref::<ErrorResponse>() {
+        Some(err) => format!("{} {}: {}", err.status, err.code, err.message),
+        None => format!("{err:#}"),
+    }
+}
+
+impl UpstreamProbes {
+    /// Probes the apps of the routes, unless probed recently or being probed.
+    /// Returns the last probe of each route, `None` for the apps without an API key.
+    async fn check<'a>(&self, routes: &[&'a ModelRoute]) -> Vec<(&'a ModelRoute, Option<Probe>)> {
+        let now = Instant::now();
+        let stale = {
+            let mut probes = self.probes.lock().unwrap();
+            // forget the apps no longer configured
+            let keys = routes
+                .iter()
+                .map(|route| probe_key(route))
+                .collect::<Vec<_>>();
+            probes.retain(|key, _| keys.contains(key));
+            routes
+                .iter()
+                .filter(|route| !route.client.config.api_key.is_empty())
+                // the probes are marked as checked as they start, so the concurrent checks keep
+                // the last probe instead of probing the apps again
+                .filter(|route| match probes.get_mut(&probe_key(route)) {
+                    Some(probe) if now.duration_since(probe.checked_at) < PROBE_TTL => false,
+                    Some(probe) => {
+                        probe.checked_at = now;
+                        true
+                    }
+                    None => {
+                        let probe = Probe {
+                            checked_at: now,
+                            reachable: false,
+                            latency_ms: 0,
+                            last_success: None,
+                            last_error: None,
+                        };
+                        probes.insert(probe_key(route), probe);
+                        true
+                    }
+                })
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        let results = futures::future::join_all(stale.iter().map(|route| async move {
+            let started = Instant::now();
+            let result = match tokio::time::timeout(
+                PROBE_TIMEOUT,
+                dify::app_parameters(&route.client, None),
+            )
+            .await
+            {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(probe_error(&e)),
+                Err(_) => Err(format!("timed out after {}s", PROBE_TIMEOUT.as_secs())),
+            };
+            (route, started.elapsed(), result)
+        }))
+        .await;
+
+        let mut probes = self.probes.lock().unwrap();
+        for (route, latency, result) in results {
+            // the app was reconfigured during the probe
+            let Some(probe) = probes.get_mut(&probe_key(route)) else {
+                continue;
+            };
+            probe.latency_ms = latency.as_millis() as u64;
+            probe.reachable = result.is_ok();
+            match result {
+                Ok(()) => probe.last_success = Some(SystemTime::now()),
+                Err(message) => {
+                    log::warn!(
+                        "Dify app of model {} is unreachable: {}",
+                        route.name,
+                        message
+                    );
+                    probe.last_error = Some((SystemTime::now(), message));
+                }
+            }
+        }
+        routes
+            .iter()
+            .map(|route| {
+                let probe = (!route.client.config.api_key.is_empty())
+                    .then(|| probes.get(&probe_key(route)).cloned())
+                    .flatten();
+                (*route, probe)
+            })
+            .collect()
+    }
+}
+
+/// Handles `GET /healthz`, the process is alive.
+pub async fn healthz_handler() -> Json<JsonValue> {
+    Json(json!({"status": "ok"}))
+}
+
+/// Handles `GET /readyz`: ready once the config has models and their Dify apps are reachable.
+pub async fn readyz_handler(State(state): State<AppState>) -> Response {
+    let gateway = state.gateway();
+    let routes = gateway.models.iter().collect::<Vec<_>>();
+    let mut reasons = Vec::new();
+    if routes.is_empty() {
+        reasons.push("no model is configured".to_string());
+    }
+    for (route, probe) in state.probes.check(&routes).await {
+        let message = match probe {
+            Some(probe) if probe.is_pending() => "not probed yet".to_string(),
+            Some(Probe {
+                reachable: false,
+                last_error,
+                ..
+            }) => last_error.map(|(_, message)| message).unwrap_or_default(),
+            _ => continue,
+        };
+        reasons.push(format!(
+            "model {} on upstream {}: {}",
+            route.name, route.upstream, message
+        ));
+    }
+    if reasons.is_empty() {
+        Json(json!({"status": "ready"})).into_response()
+    } else {
+        let body = json!({"status": "not_ready", "reasons": reasons});
+        (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+/// The status of a Dify app.
+#[derive(Serialize)]
+struct AppStatus {
+    model: String,
+    app_type: JsonValue,
+    /// `ok`, `error`, `pending` until the first probe ends, or `unchecked` without an API key.
+    status: &'static str,
+    latency_ms: Option<u64>,
+    last_success: Option<String>,
+    last_error: Option<JsonValue>,
+}
+
+/// The status of an upstream and its apps.
+#[derive(Serialize)]
+struct UpstreamStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base_url: Option<String>,
+    apps: Vec<AppStatus>,
+}
+
+/// Handles `GET /status`, the probes of the Dify apps by upstream.
+pub async fn status_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(access): Extension<AccessLog>,
+) -> Result<Json<JsonValue>, ApiError> {
+    let gateway = state.gateway();
+    // the details are only shown to the clients with a gateway key
+    let client = match gateway.config.auth.mode {
+        AuthMode::Strict => Some(authenticate(&headers, &gateway, &access)?),
+        AuthMode::Passthrough | AuthMode::Disabled => None,
+    };
+    let detailed = client.is_some();
+    let routes = gateway.models.iter().collect::<Vec<_>>();
+    let mut upstreams = BTreeMap::<String, UpstreamStatus>::new();
+    let mut ready = !routes.is_empty();
+    // all the apps are checked, so the probes of the models of other keys are kept
+    let probes = state.probes.check(&routes).await;
+    let allowed = |route: &ModelRoute| {
+        client
+            .as_ref()
+            .map_or(true, |client| client.allows(&route.name))
+    };
+    for (route, probe) in probes.into_iter().filter(|(route, _)| allowed(route)) {
+        let status = match &probe {
+            Some(probe) if probe.is_pending() => "pending",
+            Some(probe) if probe.reachable => "ok",
+            Some(_) => "error",
+            None => "unchecked",
+        };
+        ready &= status != "error" && status != "pending";
+        let app = AppStatus {
+            model: route.name.clone(),
+            app_type: serde_json::to_value(&route.app_type).unwrap_or_default(),
+            status,
+            latency_ms: probe
+                .as_ref()
+                .filter(|probe| !probe.is_pending())
+                .map(|probe| probe.latency_ms),
+            last_success: probe
+                .as_ref()
+                .and_then(|probe| probe.last_success)
+                .map(format_time),
+            last_error: probe
+                .and_then(|probe| probe.last_error)
+                .map(|(time, message)| {
+                    let mut error = json!({"time": format_time(time)});
+                    if detailed {
+                        error["message"] = message.into();
+                    }
+                    error
+                }),
+        };
+        upstreams
+            .entry(route.upstream.clone())
+            .or_insert_with(|| UpstreamStatus {
+                base_url: detailed.then(|| route.client.config.base_url.clone()),
+                apps: Vec::new(),
+            })
+            .apps
+            .push(app);
+    }
+    Ok(Json(json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_seconds": state.probes.started.elapsed().as_secs(),
+        "upstreams": upstreams,
+    })))
+}
@@ -1,27 +1,314 @@
+use super::{
+    access_log, auth::KeyStore, conversation::ConversationStore, health::UpstreamProbes,
+    inputs::ParametersCache, metrics, rate_limits::RateLimiter, registry::ModelRegistry,
+    tasks::TaskRegistry, telemetry,
+};
+use crate::config::Config;
 use axum::{
-    http::{header, StatusCode},
+    extract::{rejection::JsonRejection, FromRequest},
+    http::StatusCode,
     response::{IntoResponse, Response},
 };
-use dify_client::Client as DifyClient;
+use dify_client::response::ErrorResponse;
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    sync::{Arc, RwLock},
+};
+
+/// The gateway built from a config.
+/// A request keeps the gateway it started with, so reloading the config doesn't affect it.
+pub struct Gateway {
+    /// The config the gateway is built from.
+    pub config: Config,
+    /// Routes the requested models to the Dify apps.
+    pub models: ModelRegistry,
+    /// The keys issued to the clients.
+    pub keys: KeyStore,
+}
+
+impl Gateway {
+    /// Creates a gateway from a validated config.
+    pub fn new(config: Config) -> Self {
+        let models = ModelRegistry::new(&config);
+        let keys = KeyStore::new(&config);
+        Self {
+            config,
+            models,
+            keys,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AppState {
-    pub dify: DifyClient,
+    gateway: Arc<RwLock<Arc<Gateway>>>,
+    /// The Dify conversations of the chats, kept across config reloads.
+    pub conversations: Arc<ConversationStore>,
+    /// The Dify app parameters, cached for the inputs validation.
+    pub parameters: Arc<ParametersCache>,
+    /// The Dify tasks of the running streams, for their cancellation.
+    pub tasks: Arc<TaskRegistry>,
+    /// The usage of the keys and users, for their rate limits.
+    pub rate_limits: Arc<RateLimiter>,
+    /// The probes of the Dify apps, for the readiness and the status.
+    pub probes: Arc<UpstreamProbes>,
+}
+
+impl AppState {
+    /// Creates the state from a validated config.
+    pub fn new(config: Config) -> Self {
+        let rate_limits = RateLimiter::new(config.rate_limits.state_file.clone());
+        Self {
+            gateway: Arc::new(RwLock::new(Arc::new(Gateway::new(config)))),
+            conversations: Default::default(),
+            parameters: Default::default(),
+            tasks: Default::default(),
+            rate_limits: Arc::new(rate_limits),
+            probes: Default::default(),
+        }
+    }
+
+    /// Returns the current gateway.
+    pub fn gateway(&self) -> Arc<Gateway> {
+        self.gateway.read().unwrap().clone()
+    }
+
+    /// Replaces the gateway with one built from a new config.
+    pub fn reload(&self, config: Config) {
+        let gateway = Arc::new(Gateway::new(config));
+        *self.gateway.write().unwrap() = gateway;
+    }
+}
+
+/// An error with an explicit HTTP status, rendered as an OpenAI error object.
+#[derive(Debug)]
+pub struct ApiError {
+    /// The HTTP status code of the response.
+    pub status: StatusCode,
+    /// A human-readable error message.
+    pub message: String,
+    /// The error type, e.g. `invalid_request_error`.
+    pub type_: &'static str,
+    /// The request parameter related to the error, if any.
+    pub param: Option<String>,
+    /// The error code, e.g. `model_not_found`.
+    pub code: Option<&'static str>,
+}
+
+impl ApiError {
+    /// The requested model is not served by the gateway.
+    pub fn model_not_found(model: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message: format!("The model `{model}` does not exist or you do not have access to it."),
+            type_: "invalid_request_error",
+            param: Some("model".into()),
+            code: Some("model_not_found"),
+        }
+    }
+
+    /// The request is invalid, `param` names the offending parameter.
+    pub fn invalid_request(message: impl Into<String>, param: Option<&str>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            type_: "invalid_request_error",
+            param: param.map(Into::into),
+            code: None,
+        }
+    }
+
+    /// The request body could not be parsed.
+    pub fn invalid_body(rejection: &JsonRejection) -> Self {
+        Self {
+            status: rejection.status(),
+            ..Self::invalid_request(rejection.body_text(), None)
+        }
+    }
+
+    /// An error returned by the Dify API, mapped to the matching OpenAI error.
+    pub fn upstream(err: &ErrorResponse) -> Self {
+        let status = u16::try_from(err.status)
+            .ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::BAD_GATEWAY);
+        let (status, type_, code) = match (status, err.code.as_str()) {
+            (StatusCode::UNAUTHORIZED, _) => (status, "invalid_request_error", "invalid_api_key"),
+            (_, "provider_quota_exceeded") => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "insufficient_quota",
+                "insufficient_quota",
+            ),
+            (_, "provider_not_initialize") => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                "provider_not_initialized",
+            ),
+            (_, "invalid_param") => (status, "invalid_request_error", "invalid_param"),
+            (StatusCode::NOT_FOUND, _) => (status, "invalid_request_error", "app_not_found"),
+            (StatusCode::TOO_MANY_REQUESTS, _) => {
+                (status, "rate_limit_error", "rate_limit_exceeded")
+            }
+            (StatusCode::GATEWAY_TIMEOUT, _) => (status, "timeout", "timeout"),
+            (status, _) if status.is_client_error() => {
+                (status, "invalid_request_error", "upstream_error")
+            }
+            _ => (StatusCode::BAD_GATEWAY, "server_error", "upstream_error"),
+        };
+        Self {
+            status,
+            message: format!("upstream: {}", err.message),
+            type_,
+            param: None,
+            code: Some(code),
+        }
+    }
+
+    /// The request to the Dify API failed.
+    pub fn upstream_unavailable(err: &reqwest::Error) -> Self {
+        let (status, type_, code) = if err.is_timeout() {
+            (StatusCode::GATEWAY_TIMEOUT, "timeout", "timeout")
+        } else {
+            (
+                StatusCode::BAD_GATEWAY,
+                "server_error",
+                "upstream_unavailable",
+            )
+        };
+        Self {
+            status,
+            message: format!("upstream: {err}"),
+            type_,
+            param: None,
+            code: Some(code),
+        }
+    }
+
+    /// The client isn't authenticated.
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            message: message.into(),
+            type_: "invalid_request_error",
+            param: None,
+            code: Some("invalid_api_key"),
+        }
+    }
+
+    /// A rate limit or token quota of the gateway is reached.
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            message: message.into(),
+            type_: "rate_limit_error",
+            param: None,
+            code: Some("rate_limit_exceeded"),
+        }
+    }
+
+    /// The requested object doesn't exist.
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message: message.into(),
+            type_: "invalid_request_error",
+            param: None,
+            code: Some("not_found"),
+        }
+    }
+
+    /// The answers of the app are not valid for the JSON `response_format` of the request.
+    pub fn invalid_json_answer(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_GATEWAY,
+            message: message.into(),
+            type_: "server_error",
+            param: Some("response_format".into()),
+            code: Some("json_validation_failed"),
+        }
+    }
+
+    /// An unexpected error of the gateway.
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: message.into(),
+            type_: "server_error",
+            param: None,
+            code: None,
+        }
+    }
+
+    /// Returns the OpenAI error object, also sent as the last event of a failed stream.
+    pub fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "message": self.message,
+                "type": self.type_,
+                "param": self.param,
+                "code": self.code,
+            }
+        })
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        // only the server errors fail the request span
+        let error_type = self.code.unwrap_or(self.type_);
+        let message = self
+            .status
+            .is_server_error()
+            .then_some(self.message.as_str());
+        telemetry::record_error(&opentelemetry::Context::current(), error_type, message);
+        let response = (self.status, axum::Json(self.body())).into_response();
+        access_log::with_error(response, error_type)
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.message)
+    }
 }
 
+impl std::error::Error for ApiError {}
+
 pub struct AppError(anyhow::Error);
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        log::error!("{}", self.0);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            [(header::CONTENT_TYPE, "application/json")],
-            format!("{{\"error\": \"{}\"}}", self.0.to_string()),
-        )
-            .into_response()
+        let err = match self.0.downcast::<ApiError>() {
+            Ok(err) => err,
+            Err(e) => {
+                if let Some(err) = e.downcast_ref::<ErrorResponse>() {
+                    metrics::upstream_error(&err.code);
+                    ApiError::upstream(err)
+                } else if let Some(err) = e.downcast_ref::<reqwest::Error>() {
+                    let err = ApiError::upstream_unavailable(err);
+                    metrics::upstream_error(err.code.unwrap_or_default());
+                    err
+                } else if let Some(rejection) = e.downcast_ref::<JsonRejection>() {
+                    ApiError::invalid_body(rejection)
+                } else {
+                    log::error!("{:#}", e);
+                    return ApiError::internal(format!("{e:#}")).into_response();
+                }
+            }
+        };
+        if err.status.is_server_error() {
+            log::error!("{}", err);
+        } else {
+            log::debug!("{}", err);
+        }
+        err.into_response()
     }
 }
 
+/// A JSON request body, its rejections are rendered as OpenAI errors.
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(AppError))]
+pub struct AppJson<T>(pub T);
+
 impl<E> From<E> for AppError
 where
     E: Into<anyhow::Error>,
@@ -0,0 +1,173 @@
+//! Drives the Dify app input variables (`inputs`) from the chat requests.
+use super::{
+    dify::{self, AppInfo},
+    helper::ApiError,
+    registry::ModelRoute,
+};
+use serde_json::Value as JsonValue;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long the app parameters and info are cached.
+const PARAMETERS_TTL: Duration = Duration::from_secs(300);
+
+/// Converts a JSON value to a Dify input value, `null` means no value.
+fn input_value(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::Null => None,
+        JsonValue::String(s) => Some(s.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Merges the `metadata` and `inputs` of a request into the Dify inputs, `inputs` taking precedence.
+/// A `null` value removes the input.
+pub fn merge_request_inputs(
+    inputs: &mut HashMap<String, String>,
+    metadata: Option<&HashMap<String, JsonValue>>,
+    request_inputs: Option<&HashMap<String, JsonValue>>,
+) {
+    let metadata = metadata.into_iter().flatten();
+    let metadata = metadata.filter(|(name, _)| *name != "conversation_id");
+    for (name, value) in metadata.chain(request_inputs.into_iter().flatten()) {
+        match input_value(value) {
+            Some(value) => inputs.insert(name.clone(), value),
+            None => inputs.remove(name),
+        };
+    }
+}
+
+/// An input variable declared by a Dify app, in its `user_input_form`.
+struct InputVariable<'a> {
+    variable: &'a str,
+    required: bool,
+    options: Option<Vec<&'a str>>,
+}
+
+/// Parses the input variables of the app parameters.
+/// Each form item is an object with a single key, the control type.
+fn input_variables(parameters: &JsonValue) -> Vec<InputVariable<'_>> {
+    let Some(form) = parameters.get("user_input_form").and_then(|f| f.as_array()) else {
+        return Vec::new();
+    };
+    form.iter()
+        .filter_map(|item| item.as_object()?.values().next())
+        .filter_map(|control| {
+            Some(InputVariable {
+                variable: control.get("variable")?.as_str()?,
+                required: control.get("required").and_then(|r| r.as_bool()) == Some(true),
+                options: control
+                    .get("options")
+                    .and_then(|o| o.as_array())
+                    .map(|o| o.iter().filter_map(|v| v.as_str()).collect()),
+            })
+        })
+        .collect()
+}
+
+/// Checks the inputs against the variables declared by the app.
+pub fn validate(parameters: &JsonValue, inputs: &HashMap<String, String>) -> Result<(), ApiError> {
+    for input in input_variables(parameters) {
+        let param = format!("inputs.{}", input.variable);
+        match inputs.get(input.variable) {
+            None if input.required => {
+                let message = format!("Missing required input variable `{}`.", input.variable);
+                return Err(ApiError::invalid_request(message, Some(&param)));
+            }
+            Some(value) if value.is_empty() && input.required => {
+                let message = format!("The input variable `{}` is required.", input.variable);
+                return Err(ApiError::invalid_request(message, Some(&param)));
+            }
+            Some(value) => {
+                let options = input.options.unwrap_or_default();
+                if !options.is_empty() && !value.is_empty() && !options.contains(&value.as_str()) {
+                    let message = format!(
+                        "Invalid value `{}` of the input variable `{}`, expected one of: {}.",
+                        value,
+                        input.variable,
+                        options.join(", ")
+                    );
+                    return Err(ApiError::invalid_request(message, Some(&param)));
+                }
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Cached values, by model and API key.
+type Entries<T> = Mutex<HashMap<(String, String), (Instant, T)>>;
+
+/// Returns the cached value of a model, fetched when missing or expired.
+async fn cached<T: Clone, F: Future<Output = anyhow::Result<T>>>(
+    entries: &Entries<T>,
+    route: &ModelRoute,
+    token: Option<&str>,
+    fetch: impl FnOnce() -> F,
+) -> anyhow::Result<T> {
+    let key = (route.name.clone(), token.unwrap_or_default().to_owned());
+    if let Some((fetched_at, value)) = entries.lock().unwrap().get(&key) {
+        if fetched_at.elapsed() < PARAMETERS_TTL {
+            return Ok(value.clone());
+        }
+    }
+    let value = fetch().await?;
+    let mut entries = entries.lock().unwrap();
+    entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < PARAMETERS_TTL);
+    entries.insert(key, (Instant::now(), value.clone()));
+    Ok(value)
+}
+
+/// Caches the app parameters and info, by model and API key.
+#[derive(Default)]
+pub struct ParametersCache {
+    entries: Entries<JsonValue>,
+    info: Entries<AppInfo>,
+}
+
+impl ParametersCache {
+    /// Returns the parameters of the app of a model, fetched from Dify when not cached.
+    pub async fn get(&self, route: &ModelRoute, token: Option<&str>) -> anyhow::Result<JsonValue> {
+        cached(&self.entries, route, token, || {
+            dify::app_parameters(&route.client, token)
+        })
+        .await
+    }
+
+    /// Returns the info of the app of a model, fetched from Dify when not cached.
+    pub async fn info(&self, route: &ModelRoute, token: Option<&str>) -> anyhow::Result<AppInfo> {
+        cached(&self.info, route, token, || {
+            dify::app_info(&route.client, token)
+        })
+        .await
+    }
+
+    /// Validates the inputs of a model, if enabled.
+    /// The validation is skipped when the parameters can't be fetched.
+    pub async fn validate(
+        &self,
+        route: &ModelRoute,
+        token: Option<&str>,
+        inputs: &HashMap<String, String>,
+    ) -> Result<(), ApiError> {
+        if !route.validate_inputs {
+            return Ok(());
+        }
+        match self.get(route, token).await {
+            Ok(parameters) => validate(&parameters, inputs),
+            Err(e) => {
+                log::warn!(
+                    "Failed to fetch app parameters of model {}, inputs not validated: {}",
+                    route.name,
+                    e
+                );
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Collects the metrics of the gateway, exposed in the Prometheus text format on `GET /metrics`.
+//!
+//! The requests are counted by a middleware, labelled with the model the handlers set on the
+//! responses. The streams, the upstream errors and the token usage are recorded as they happen.
+use super::{access_log::AccessLog, answer::TokenUsage};
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::{sync::OnceLock, time::Instant};
+
+struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    first_token: HistogramVec,
+    stream_duration: HistogramVec,
+    streams: IntGaugeVec,
+    upstream_errors: IntCounterVec,
+    prompt_tokens: IntCounterVec,
+    completion_tokens: IntCounterVec,
+    total_tokens: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            register(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), labels)?,
+            )
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str], buckets: Option<Vec<f64>>| {
+            let mut opts = HistogramOpts::new(name, help);
+            if let Some(buckets) = buckets {
+                opts = opts.buckets(buckets);
+            }
+            register(&registry, HistogramVec::new(opts, labels)?)
+        };
+        Ok(Self {
+            requests: counter(
+                "dify_gateway_requests_total",
+                "The HTTP requests, by route, model and status.",
+                &["route", "model", "status"],
+            )?,
+            request_duration: histogram(
+                "dify_gateway_request_duration_seconds",
+                "The time to answer the HTTP requests, until the response headers for the streams.",
+                &["route", "model", "status"],
+                None,
+            )?,
+            first_token: histogram(
+                "dify_gateway_time_to_first_token_seconds",
+                "The time from a stream request to its first answer token.",
+                &["model"],
+                Some(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+            )?,
+            stream_duration: histogram(
+                "dify_gateway_stream_duration_seconds",
+                "The time from a stream request to the end of its stream.",
+                &["model"],
+                Some(vec![1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+            )?,
+            streams: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("dify_gateway_streams", "The streams in flight."),
+                    &["model"],
+                )?,
+            )?,
+            upstream_errors: counter(
+                "dify_gateway_upstream_errors_total",
+                "The errors of the Dify API, by Dify error code.",
+                &["code"],
+            )?,
+            prompt_tokens: counter(
+                "dify_gateway_prompt_tokens_total",
+                "The prompt tokens reported by Dify.",
+                &["model"],
+            )?,
+            completion_tokens: counter(
+                "dify_gateway_completion_tokens_total",
+                "The completion tokens reported by Dify.",
+                &["model"],
+            )?,
+            total_tokens: counter(
+                "dify_gateway_tokens_total",
+                "The total tokens reported by Dify, the workflows only report this one.",
+                &["model"],
+            )?,
+            registry,
+        })
+    }
+}
+
+fn register<M: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: M,
+) -> prometheus::Result<M> {
+    registry.register(Box::new(metric.clone()))?;
+    Ok(metric)
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("invalid metric definitions"))
+}
+
+/// The model of a request, set on the responses for the request metrics.
+#[derive(Clone)]
+struct ModelLabel(String);
+
+/// Labels a response with the model of its request, if the model is known.
+pub fn with_model(mut response: Response, model: Option<&str>) -> Response {
+    if let Some(model) = model {
+        response
+            .extensions_mut()
+            .insert(ModelLabel(model.to_owned()));
+    }
+    response
+}
+
+/// Counts the requests and their duration.
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let started = Instant::now();
+    let response = next.run(req).await;
+    let model = response.extensions().get::<ModelLabel>();
+    let status = response.status().as_u16().to_string();
+    let labels = [
+        route.as_str(),
+        model.map(|model| model.0.as_str()).unwrap_or_default(),
+        status.as_str(),
+    ];
+    let metrics = metrics();
+    metrics.requests.with_label_values(&labels).inc();
+    metrics
+        .request_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+/// Counts an error of the Dify API.
+pub fn upstream_error(code: &str) {
+    let code = if code.is_empty() { "unknown" } else { code };
+    metrics().upstream_errors.with_label_values(&[code]).inc();
+}
+
+/// Counts the tokens of an answer.
+pub fn usage(model: &str, usage: TokenUsage) {
+    let metrics = metrics();
+    let counters = [
+        (&metrics.prompt_tokens, usage.prompt_tokens),
+        (&metrics.completion_tokens, usage.completion_tokens),
+        (&metrics.total_tokens, usage.total_tokens),
+    ];
+    for (counter, tokens) in counters {
+        counter.with_label_values(&[model]).inc_by(tokens);
+    }
+}
+
+/// Measures a stream, in flight until dropped.
+/// The access log of its request is written once it ends.
+pub struct StreamMetrics {
+    model: String,
+    access: AccessLog,
+    first_token: bool,
+}
+
+impl StreamMetrics {
+    /// Starts measuring the stream of a request.
+    pub fn start(model: &str, access: AccessLog) -> Self {
+        metrics().streams.with_label_values(&[model]).inc();
+        Self {
+            model: model.to_owned(),
+            access,
+            first_token: false,
+        }
+    }
+
+    /// Records the time to the first token, on the first call.
+    pub fn token(&mut self) {
+        if !self.first_token {
+            self.first_token = true;
+            self.access.first_token();
+            metrics()
+                .first_token
+                .with_label_values(&[&self.model])
+                .observe(self.access.started().elapsed().as_secs_f64());
+        }
+    }
+
+    /// Counts the tokens of an answer of the stream.
+    pub fn usage(&self, tokens: TokenUsage) {
+        usage(&self.model, tokens);
+        self.access.usage(tokens);
+    }
+}
+
+impl Drop for StreamMetrics {
+    fn drop(&mut self) {
+        let metrics = metrics();
+        metrics.streams.with_label_values(&[&self.model]).dec();
+        metrics
+            .stream_duration
+            .with_label_values(&[&self.model])
+            .observe(self.access.started().elapsed().as_secs_f64());
+    }
+}
+
+/// Handles `GET /metrics`, in the Prometheus text format.
+pub async fn metrics_handler() -> Response {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&metrics().registry.gather(), &mut buffer) {
+        log::error!("Failed to encode the metrics: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let content_type = encoder.format_type().to_owned();
+    ([(header::CONTENT_TYPE, content_type)], buffer).into_response()
+}
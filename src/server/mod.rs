@@ -1,4 +1,21 @@
+mod access_log;
+mod answer;
+mod audit;
+mod auth;
+mod conversation;
+mod dify;
+mod files;
+mod health;
 mod helper;
+mod inputs;
+mod metrics;
+mod query;
+mod rate_limits;
+mod registry;
+mod structured;
+mod tasks;
+mod telemetry;
+mod tools;
 mod v1_handlers;
 
 use axum::{
@@ -13,7 +30,11 @@ use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 use v1_handlers::*;
 
+pub use access_log::init_access_log;
+pub use audit::{init_audit_log, StreamAssembler};
+pub use auth::{generate_key, hash_key};
 pub use helper::AppState;
+pub use telemetry::init_tracing;
 
 async fn html_handler() -> (HeaderMap, &'static [u8]) {
     let headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
@@ -27,12 +48,28 @@ pub fn app_routes() -> Router<AppState> {
         .allow_origin(Any);
 
     let v1_routes = Router::new()
-        .route("/chat/completions", post(chat_completions_handler))
+        .route(
+            "/chat/completions",
+            post(chat_completions_handler).layer(middleware::from_fn(audit::record)),
+        )
+        .route(
+            "/chat/completions/:id/cancel",
+            post(cancel_chat_completion_handler),
+        )
+        .route("/completions", post(completions_handler))
+        .route("/models", get(models_handler))
+        .route("/models/:model", get(model_handler))
         .route_layer(middleware::from_fn(check_method))
+        .route_layer(middleware::from_fn(metrics::track))
+        .route_layer(middleware::from_fn(telemetry::trace))
         .layer(ServiceBuilder::new().layer(cors));
 
-    let app_routes = Router::new()
+    Router::new()
         .route("/", get(html_handler))
-        .nest("/v1", v1_routes);
-    app_routes
+        .route("/healthz", get(health::healthz_handler))
+        .route("/readyz", get(health::readyz_handler))
+        .route("/status", get(health::status_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .nest("/v1", v1_routes)
+        .layer(middleware::from_fn(access_log::log))
 }
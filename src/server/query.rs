@@ -0,0 +1,199 @@
+//! Builds the Dify `query` from the chat messages.
+use crate::config::{QueryConfig, QueryMode};
+
+/// The default template of the `history` mode.
+pub const DEFAULT_TEMPLATE: &str =
+    "here is our talk history:\n'''\n{history}\n'''\n\nhere is my question:\n{question}";
+/// The default template of each message of the history.
+pub const DEFAULT_MESSAGE_TEMPLATE: &str = "{role}: {content}";
+/// The default separator of the history messages.
+pub const DEFAULT_SEPARATOR: &str = "\n";
+
+/// How the messages of a model are turned into a Dify query.
+#[derive(Clone, Debug)]
+pub struct QueryBuilder {
+    mode: QueryMode,
+    template: String,
+    message_template: String,
+    separator: String,
+}
+
+impl QueryBuilder {
+    /// Resolves the query settings of a model, falling back to the global ones, then the defaults.
+    pub fn new(model: Option<&QueryConfig>, global: &QueryConfig) -> Self {
+        let pick = |get: fn(&QueryConfig) -> Option<&String>, default: &str| {
+            model
+                .and_then(get)
+                .or(get(global))
+                .cloned()
+                .unwrap_or(default.into())
+        };
+        Self {
+            mode: model
+                .and_then(|q| q.mode)
+                .or(global.mode)
+                .unwrap_or_default(),
+            template: pick(|q| q.template.as_ref(), DEFAULT_TEMPLATE),
+            message_template: pick(|q| q.message_template.as_ref(), DEFAULT_MESSAGE_TEMPLATE),
+            separator: pick(|q| q.separator.as_ref(), DEFAULT_SEPARATOR),
+        }
+    }
+
+    /// Builds the query from the `(role, content)` of the messages to send, the last one being the question.
+    /// `continued` tells the messages extend a Dify conversation, which already holds the history.
+    pub fn build(&self, messages: &[(&str, &str)], continued: bool) -> String {
+        let Some(((_, question), history)) = messages.split_last() else {
+            return String::new();
+        };
+        match self.mode {
+            QueryMode::LastMessage => question.to_string(),
+            QueryMode::JsonMessages => {
+                let messages = messages
+                    .iter()
+                    .map(|(role, content)| serde_json::json!({ "role": role, "content": content }))
+                    .collect::<Vec<_>>();
+                serde_json::Value::Array(messages).to_string()
+            }
+            QueryMode::History => {
+                // system messages go to `{system}` if the template uses it, to the history otherwise
+                let split_system = self.template.contains("{system}");
+                let (system, history): (Vec<_>, Vec<_>) = history
+                    .iter()
+                    .partition(|(role, _)| split_system && *role == "system");
+                if history.is_empty() && (system.is_empty() || continued) {
+                    return question.to_string();
+                }
+                let system = system
+                    .iter()
+                    .map(|(_, content)| *content)
+                    .collect::<Vec<_>>()
+                    .join(&self.separator);
+                let history = history
+                    .iter()
+                    .map(|(role, content)| {
+                        render(
+                            &self.message_template,
+                            &[("role", role), ("content", content)],
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(&self.separator);
+                render(
+                    &self.template,
+                    &[
+                        ("system", &system),
+                        ("history", &history),
+                        ("question", question),
+                    ],
+                )
+            }
+        }
+    }
+}
+
+/// Renders the `{name}` placeholders of a template in a single pass,
+/// so placeholders in the substituted values are left untouched.
+/// Unknown placeholders are kept as is.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            let (_, value) = values.iter().find(|(n, _)| *n == name)?;
+            Some((end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                output.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(mode: QueryMode) -> QueryBuilder {
+        let config = QueryConfig {
+            mode: Some(mode),
+            ..Default::default()
+        };
+        QueryBuilder::new(Some(&config), &QueryConfig::default())
+    }
+
+    const MESSAGES: [(&str, &str); 3] = [
+        ("system", "be brief"),
+        ("user", "hi"),
+        ("user", "how are you?"),
+    ];
+
+    #[test]
+    fn history_mode() {
+        let query = builder(QueryMode::History).build(&MESSAGES, false);
+        assert_eq!(
+            query,
+            "here is our talk history:\n'''\nsystem: be brief\nuser: hi\n'''\n\n\
+             here is my question:\nhow are you?"
+        );
+        // a single message, or a continued conversation, is sent as is
+        let query = builder(QueryMode::History).build(&MESSAGES[2..], false);
+        assert_eq!(query, "how are you?");
+    }
+
+    #[test]
+    fn history_mode_with_system_placeholder() {
+        let config = QueryConfig {
+            template: Some("{system}|{history}|{question}".into()),
+            ..Default::default()
+        };
+        let builder = QueryBuilder::new(Some(&config), &QueryConfig::default());
+        assert_eq!(
+            builder.build(&MESSAGES, false),
+            "be brief|user: hi|how are you?"
+        );
+        assert_eq!(
+            builder.build(&[MESSAGES[0], MESSAGES[2]], true),
+            "how are you?"
+        );
+    }
+
+    #[test]
+    fn last_message_mode() {
+        let query = builder(QueryMode::LastMessage).build(&MESSAGES, false);
+        assert_eq!(query, "how are you?");
+    }
+
+    #[test]
+    fn json_messages_mode() {
+        let query = builder(QueryMode::JsonMessages).build(&MESSAGES[1..], false);
+        assert_eq!(
+            query,
+            r#"[{"content":"hi","role":"user"},{"content":"how are you?","role":"user"}]"#
+        );
+    }
+
+    #[test]
+    fn render_is_single_pass() {
+        let rendered = render(
+            "{history} / {question} / {unknown}",
+            &[("history", "{question}"), ("question", "{history} {x}")],
+        );
+        assert_eq!(rendered, "{question} / {history} {x} / {unknown}");
+    }
+
+    #[test]
+    fn render_unclosed_placeholder() {
+        assert_eq!(render("{a} {b", &[("a", "1"), ("b", "2")]), "1 {b");
+    }
+}
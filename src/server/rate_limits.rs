@@ -0,0 +1,645 @@
+//! Limits the completion requests of the keys and of the users of a key.
+//!
+//! Each key and user has a bucket counting its requests of the current minute, its tokens of the
+//! current UTC day and its running streams. A request is only admitted if none of its buckets is
+//! over a limit, and is charged with the tokens Dify reports once its answers end.
+//! The buckets may be saved to a file, so the limits survive restarts.
+//!
+//! The users are named by the clients, so their buckets are identified by a hash of the name, and
+//! the users of a key beyond the first `MAX_USERS_PER_KEY` share a bucket.
+use super::{
+    answer::{AnswerEvent, TokenUsage},
+    auth::{hash_key, Client},
+    helper::ApiError,
+};
+use crate::config::{RateLimits, RateLimitsConfig};
+use axum::{
+    http::{HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MINUTE: u64 = 60;
+const DAY: u64 = 24 * 60 * 60;
+/// The users of a key with their own bucket, the others share one.
+const MAX_USERS_PER_KEY: usize = 10_000;
+/// The characters of a user name shown in the error messages.
+const MAX_USER_LABEL: usize = 64;
+/// Separates the key id from the user in the bucket ids.
+const USER_SCOPE: &str = "/user:";
+/// The user of the bucket shared by the users beyond `MAX_USERS_PER_KEY`.
+const OTHER_USERS: &str = "*";
+
+/// The usage of a key or user.
+#[derive(Serialize, Deserialize, Default)]
+struct Bucket {
+    /// The start of the current minute, in Unix seconds.
+    window: u64,
+    /// The requests of the current minute.
+    requests: u64,
+    /// The current UTC day, in days since the Unix epoch.
+    day: u64,
+    /// The tokens of the current day.
+    tokens: u64,
+    /// The running streams, not saved as they end with the process.
+    #[serde(skip)]
+    streams: u64,
+}
+
+impl Bucket {
+    /// Starts a new minute or day once the current one is over.
+    fn refresh(&mut self, now: u64) {
+        if now >= self.window + MINUTE {
+            self.window = now;
+            self.requests = 0;
+        }
+        if now / DAY != self.day {
+            self.day = now / DAY;
+            self.tokens = 0;
+        }
+    }
+
+    /// Returns whether the bucket has no usage to remember.
+    fn is_idle(&self, now: u64) -> bool {
+        let day_over = self.tokens == 0 || now / DAY != self.day;
+        self.streams == 0 && now >= self.window + MINUTE && day_over
+    }
+
+    /// Returns the state of the limits set on the bucket.
+    fn limits(&self, limits: &RateLimits, now: u64) -> Vec<Limit> {
+        let requests = limits.requests_per_minute.map(|limit| Limit {
+            name: "requests",
+            limit,
+            used: self.requests,
+            reset: Some(self.window + MINUTE - now),
+        });
+        let tokens = limits.tokens_per_day.map(|limit| Limit {
+            name: "tokens",
+            limit,
+            used: self.tokens,
+            reset: Some((self.day + 1) * DAY - now),
+        });
+        let streams = limits.concurrent_streams.map(|limit| Limit {
+            name: "streams",
+            limit,
+            used: self.streams,
+            reset: None,
+        });
+        [requests, tokens, streams].into_iter().flatten().collect()
+    }
+}
+
+/// The state of a limit, as reported in the `x-ratelimit-*` headers.
+#[derive(Clone, Copy)]
+struct Limit {
+    /// The name of the limit in the headers.
+    name: &'static str,
+    limit: u64,
+    used: u64,
+    /// The seconds until the limit resets, the streams are released as they end instead.
+    reset: Option<u64>,
+}
+
+impl Limit {
+    fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+
+    fn add_headers(&self, headers: &mut HeaderMap) {
+        let mut insert = |kind: &str, value: String| {
+            let name = HeaderName::try_from(format!("x-ratelimit-{kind}-{}", self.name));
+            if let (Ok(name), Ok(value)) = (name, HeaderValue::try_from(value)) {
+                headers.insert(name, value);
+            }
+        };
+        insert("limit", self.limit.to_string());
+        insert("remaining", self.remaining().to_string());
+        if let Some(reset) = self.reset {
+            insert("reset", format_duration(reset));
+        }
+    }
+}
+
+/// Formats a duration in seconds like OpenAI, e.g. `6m0s`.
+fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{seconds}s"),
+        (0, _) => format!("{minutes}m{seconds}s"),
+        _ => format!("{hours}h{minutes}m{seconds}s"),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A bucket a request is counted in, with its limits.
+struct Scope {
+    id: String,
+    /// Describes the bucket in the error messages.
+    label: String,
+    limits: RateLimits,
+    /// The key id of a user bucket.
+    key_id: Option<String>,
+}
+
+/// Returns the buckets of a request, those without limits are left out.
+fn scopes(config: &RateLimitsConfig, client: &Client, user: Option<&str>) -> Vec<Scope> {
+    let key_id = client.id().unwrap_or_else(|| "anonymous".into());
+    let key_limits = match client {
+        Client::Key { key, .. } => key.rate_limits.or(&config.key),
+        Client::Passthrough(_) | Client::Anonymous => config.key.clone(),
+    };
+    let key = Scope {
+        id: key_id.clone(),
+        label: "the API key".into(),
+        limits: key_limits,
+        key_id: None,
+    };
+    let user = user.map(|user| {
+        let label = match user.char_indices().nth(MAX_USER_LABEL) {
+            Some((end, _)) => format!("{}...", &user[..end]),
+            None => user.to_string(),
+        };
+        Scope {
+            id: format!("{key_id}{USER_SCOPE}{}", &hash_key(user)[..32]),
+            label: format!("the user `{label}`"),
+            limits: config.user.clone(),
+            key_id: Some(key_id),
+        }
+    });
+    std::iter::once(key)
+        .chain(user)
+        .filter(|scope| !scope.limits.is_empty())
+        .collect()
+}
+
+/// The buckets of the keys and users, by bucket id.
+#[derive(Default)]
+struct Buckets {
+    by_id: HashMap<String, Bucket>,
+    /// The number of user buckets of each key id.
+    users: HashMap<String, usize>,
+}
+
+impl Buckets {
+    fn new(by_id: HashMap<String, Bucket>) -> Self {
+        let mut buckets = Self {
+            by_id,
+            users: HashMap::new(),
+        };
+        buckets.count_users();
+        buckets
+    }
+
+    fn count_users(&mut self) {
+        self.users.clear();
+        for id in self.by_id.keys() {
+            if let Some((key_id, user)) = id.rsplit_once(USER_SCOPE) {
+                if user != OTHER_USERS {
+                    *self.users.entry(key_id.to_string()).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// Moves a new user to the shared bucket of its key once the key has too many users.
+    fn cap_users(&mut self, scope: &mut Scope) {
+        let Some(key_id) = scope.key_id.as_ref() else {
+            return;
+        };
+        if self.by_id.contains_key(&scope.id) {
+            return;
+        }
+        let users = self.users.entry(key_id.clone()).or_default();
+        if *users < MAX_USERS_PER_KEY {
+            *users += 1;
+            return;
+        }
+        scope.id = format!("{key_id}{USER_SCOPE}{OTHER_USERS}");
+        scope.label = "the other users of the API key".into();
+    }
+}
+
+/// The usage of the keys and users, by bucket id.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    /// The file the buckets are saved to.
+    path: Option<PathBuf>,
+    /// Whether the buckets changed since they were saved.
+    changed: AtomicBool,
+}
+
+impl RateLimiter {
+    /// Creates the limiter, with the buckets saved to `path` if any.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let buckets = path.as_ref().map(|path| {
+            let buckets = match std::fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                    log::warn!("Invalid rate limits file {}: {}", path.display(), e);
+                    HashMap::new()
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => {
+                    log::warn!("Failed to read rate limits file {}: {}", path.display(), e);
+                    HashMap::new()
+                }
+            };
+            log::info!("Loaded {} rate limit buckets", buckets.len());
+            buckets
+        });
+        Self {
+            buckets: Mutex::new(Buckets::new(buckets.unwrap_or_default())),
+            path,
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// Admits a request of a client if none of its limits is reached, and counts it.
+    /// The stream requests take a stream of their buckets, until the lease is dropped.
+    pub fn admit(
+        self: &Arc<Self>,
+        config: &RateLimitsConfig,
+        client: &Client,
+        user: Option<&str>,
+        stream: bool,
+    ) -> Result<Lease, RateLimited> {
+        let mut scopes = scopes(config, client, user);
+        let now = unix_now();
+        let mut headers = HeaderMap::new();
+        if !scopes.is_empty() {
+            let mut buckets = self.buckets.lock().unwrap();
+            for scope in &mut scopes {
+                buckets.cap_users(scope);
+            }
+            // the request is checked against all its buckets before being counted
+            for scope in &scopes {
+                let bucket = buckets.by_id.entry(scope.id.clone()).or_default();
+                bucket.refresh(now);
+                let reached = bucket
+                    .limits(&scope.limits, now)
+                    .into_iter()
+                    .filter(|limit| stream || limit.name != "streams")
+                    .find(|limit| limit.remaining() == 0);
+                if let Some(limit) = reached {
+                    return Err(RateLimited::new(&scope.label, limit));
+                }
+            }
+            // the most restrictive bucket is reported for each limit
+            let mut reported: Vec<Limit> = Vec::new();
+            for scope in &scopes {
+                let bucket = buckets.by_id.get_mut(&scope.id).unwrap();
+                bucket.requests += 1;
+                bucket.streams += u64::from(stream);
+                for limit in bucket.limits(&scope.limits, now) {
+                    match reported.iter_mut().find(|other| other.name == limit.name) {
+                        Some(other) if other.remaining() <= limit.remaining() => {}
+                        Some(other) => *other = limit,
+                        None => reported.push(limit),
+                    }
+                }
+            }
+            for limit in reported {
+                limit.add_headers(&mut headers);
+            }
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        let admission = Admission {
+            limiter: self.clone(),
+            scopes: scopes.into_iter().map(|scope| scope.id).collect(),
+            stream,
+            headers,
+        };
+        Ok(Lease(Arc::new(admission)))
+    }
+
+    /// Forgets the idle buckets, then saves the others if a file is configured.
+    pub fn maintain(&self) {
+        let now = unix_now();
+        let text = {
+            let mut buckets = self.buckets.lock().unwrap();
+            buckets.by_id.retain(|_, bucket| !bucket.is_idle(now));
+            buckets.count_users();
+            if self.path.is_none() || !self.changed.swap(false, Ordering::Relaxed) {
+                return;
+            }
+            serde_json::to_string(&buckets.by_id)
+        };
+        let Some(path) = self.path.as_ref() else {
+            return;
+        };
+        // the file is replaced at once, so it's never read half written
+        let temp = path.with_extension("tmp");
+        let saved = text
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(std::fs::write(&temp, text)?))
+            .and_then(|_| Ok(std::fs::rename(&temp, path)?));
+        if let Err(e) = saved {
+            log::warn!("Failed to save rate limits file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The admission of a request, charged with the tokens of its answers.
+struct Admission {
+    limiter: Arc<RateLimiter>,
+    /// The ids of the buckets of the request.
+    scopes: Vec<String>,
+    stream: bool,
+    headers: HeaderMap,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if !self.stream || self.scopes.is_empty() {
+            return;
+        }
+        let mut buckets = self.limiter.buckets.lock().unwrap();
+        for id in &self.scopes {
+            if let Some(bucket) = buckets.by_id.get_mut(id) {
+                bucket.streams = bucket.streams.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// An admitted request, its stream is released when the last clone is dropped.
+#[derive(Clone)]
+pub struct Lease(Arc<Admission>);
+
+impl Lease {
+    /// Returns the `x-ratelimit-*` headers of the request.
+    pub fn headers(&self) -> HeaderMap {
+        self.0.headers.clone()
+    }
+
+    /// Charges the buckets of the request with the tokens of an answer.
+    pub fn charge(&self, usage: TokenUsage) {
+        if self.0.scopes.is_empty() || usage.total_tokens == 0 {
+            return;
+        }
+        let now = unix_now();
+        let mut buckets = self.0.limiter.buckets.lock().unwrap();
+        for id in &self.0.scopes {
+            let bucket = buckets.by_id.entry(id.clone()).or_default();
+            bucket.refresh(now);
+            bucket.tokens += usage.total_tokens;
+        }
+        self.0.limiter.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Charges the tokens of a streamed answer as it finishes, and holds the lease until then.
+    pub fn meter(
+        &self,
+        events: impl Stream<Item = AnswerEvent>,
+    ) -> impl Stream<Item = AnswerEvent> {
+        let lease = self.clone();
+        events.map(move |event| {
+            if let AnswerEvent::Finished(usage) = &event {
+                lease.charge(*usage);
+            }
+            event
+        })
+    }
+}
+
+/// A request over a limit, rendered as a 429 error with the state of the limit.
+pub struct RateLimited {
+    message: String,
+    limit: Limit,
+}
+
+impl RateLimited {
+    fn new(label: &str, limit: Limit) -> Self {
+        let message = match (limit.name, limit.reset) {
+            ("streams", _) | (_, None) => format!(
+                "Too many concurrent streams for {label}, limit {}. Please try again once one of them ends.",
+                limit.limit
+            ),
+            ("tokens", Some(reset)) => format!(
+                "Token quota reached for {label} on tokens per day, limit {}. Please try again in {}.",
+                limit.limit,
+                format_duration(reset)
+            ),
+            (_, Some(reset)) => format!(
+                "Rate limit reached for {label} on requests per minute, limit {}. Please try again in {}.",
+                limit.limit,
+                format_duration(reset)
+            ),
+        };
+        Self { message, limit }
+    }
+}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        log::debug!("{}", self.message);
+        let mut headers = HeaderMap::new();
+        self.limit.add_headers(&mut headers);
+        if let Some(reset) = self.limit.reset {
+            headers.insert("retry-after", HeaderValue::from(reset));
+        }
+        (headers, ApiError::rate_limited(self.message)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(requests: Option<u64>, streams: Option<u64>, tokens: Option<u64>) -> RateLimits {
+        RateLimits {
+            requests_per_minute: requests,
+            concurrent_streams: streams,
+            tokens_per_day: tokens,
+        }
+    }
+
+    fn limiter() -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(None))
+    }
+
+    fn header(lease: &Lease, name: &str) -> Option<String> {
+        let headers = lease.headers();
+        let value = headers.get(name)?;
+        Some(value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn bucket_refresh() {
+        let now = 100 * DAY + 30;
+        let mut bucket = Bucket {
+            window: now - 10,
+            requests: 3,
+            day: now / DAY,
+            tokens: 500,
+            streams: 1,
+        };
+        bucket.refresh(now);
+        assert_eq!(
+            (bucket.window, bucket.requests, bucket.tokens),
+            (now - 10, 3, 500)
+        );
+
+        // a new minute starts at the request, not at the top of the minute
+        bucket.refresh(now + 50);
+        assert_eq!(
+            (bucket.window, bucket.requests, bucket.tokens),
+            (now + 50, 0, 500)
+        );
+
+        bucket.requests = 2;
+        bucket.refresh(101 * DAY);
+        assert_eq!((bucket.requests, bucket.day, bucket.tokens), (0, 101, 0));
+        assert_eq!(bucket.streams, 1);
+    }
+
+    #[test]
+    fn admit_reports_most_restrictive_limits() {
+        let config = RateLimitsConfig {
+            key: limits(Some(10), None, Some(1000)),
+            user: limits(Some(2), Some(1), None),
+            state_file: None,
+        };
+        let limiter = limiter();
+        let lease = limiter
+            .admit(&config, &Client::Anonymous, Some("alice"), false)
+            .ok()
+            .unwrap();
+        // the user has 1 request left, the key 9
+        assert_eq!(header(&lease, "x-ratelimit-limit-requests").unwrap(), "2");
+        assert_eq!(
+            header(&lease, "x-ratelimit-remaining-requests").unwrap(),
+            "1"
+        );
+        // only the key limits the tokens, and only the user the streams
+        assert_eq!(
+            header(&lease, "x-ratelimit-remaining-tokens").unwrap(),
+            "1000"
+        );
+        assert_eq!(
+            header(&lease, "x-ratelimit-remaining-streams").unwrap(),
+            "1"
+        );
+        assert!(header(&lease, "x-ratelimit-reset-streams").is_none());
+
+        // another user is only limited by the key
+        let lease = limiter
+            .admit(&config, &Client::Anonymous, Some("bob"), false)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            header(&lease, "x-ratelimit-remaining-requests").unwrap(),
+            "1"
+        );
+        let lease = limiter
+            .admit(&config, &Client::Anonymous, None, false)
+            .ok()
+            .unwrap();
+        assert_eq!(header(&lease, "x-ratelimit-limit-requests").unwrap(), "10");
+        assert_eq!(
+            header(&lease, "x-ratelimit-remaining-requests").unwrap(),
+            "7"
+        );
+
+        assert!(limiter
+            .admit(&config, &Client::Anonymous, Some("alice"), false)
+            .is_ok());
+        let limited = limiter
+            .admit(&config, &Client::Anonymous, Some("alice"), false)
+            .err()
+            .unwrap();
+        assert_eq!(limited.limit.name, "requests");
+        assert!(limited.message.contains("the user `alice`"));
+    }
+
+    #[test]
+    fn lease_drop_releases_stream() {
+        let config = RateLimitsConfig {
+            key: limits(None, Some(1), None),
+            ..Default::default()
+        };
+        let limiter = limiter();
+        let lease = limiter
+            .admit(&config, &Client::Anonymous, None, true)
+            .ok()
+            .unwrap();
+        let clone = lease.clone();
+        // the non-stream requests don't take a stream
+        assert!(limiter
+            .admit(&config, &Client::Anonymous, None, false)
+            .is_ok());
+        assert!(limiter
+            .admit(&config, &Client::Anonymous, None, true)
+            .is_err());
+
+        drop(lease);
+        assert!(limiter
+            .admit(&config, &Client::Anonymous, None, true)
+            .is_err());
+        drop(clone);
+        assert_eq!(
+            limiter.buckets.lock().unwrap().by_id["anonymous"].streams,
+            0
+        );
+        assert!(limiter
+            .admit(&config, &Client::Anonymous, None, true)
+            .is_ok());
+    }
+
+    #[test]
+    fn user_buckets_are_hashed_and_capped() {
+        let config = RateLimitsConfig {
+            user: limits(Some(5), None, None),
+            ..Default::default()
+        };
+        let limiter = limiter();
+        let long = "x".repeat(10_000);
+        let lease = limiter
+            .admit(&config, &Client::Anonymous, Some(&long), false)
+            .ok()
+            .unwrap();
+        let id = &lease.0.scopes[0];
+        assert_eq!(id.len(), "anonymous".len() + USER_SCOPE.len() + 32);
+        for _ in 0..4 {
+            assert!(limiter
+                .admit(&config, &Client::Anonymous, Some(&long), false)
+                .is_ok());
+        }
+        let limited = limiter
+            .admit(&config, &Client::Anonymous, Some(&long), false)
+            .err()
+            .unwrap();
+        assert!(limited.message.len() < 200);
+
+        for user in 1..MAX_USERS_PER_KEY {
+            let user = user.to_string();
+            assert!(limiter
+                .admit(&config, &Client::Anonymous, Some(&user), false)
+                .is_ok());
+        }
+        // the users beyond the cap share a bucket
+        let lease = limiter
+            .admit(&config, &Client::Anonymous, Some("late"), false)
+            .ok()
+            .unwrap();
+        assert_eq!(lease.0.scopes, ["anonymous/user:*"]);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_id.len(), MAX_USERS_PER_KEY + 1);
+        assert_eq!(buckets.users["anonymous"], MAX_USERS_PER_KEY);
+    }
+}
@@ -0,0 +1,107 @@
+use super::{query::QueryBuilder, tools};
+use crate::config::{Config, QueryConfig, QueryMode, ReasoningMode, WorkflowConfig};
+use dify_client::{response::AppMode, Client as DifyClient, Config as DifyConfig};
+use std::{collections::BTreeMap, time::Duration};
+
+/// The Dify app a model is routed to.
+#[derive(Clone, Debug)]
+pub struct ModelRoute {
+    /// The model name exposed to the clients.
+    pub name: String,
+    /// The name of the upstream serving the app.
+    pub upstream: String,
+    /// The Dify client, configured with the app's base URL and API key.
+    pub client: DifyClient,
+    /// The type of the Dify app.
+    pub app_type: AppMode,
+    /// Builds the Dify query from the chat messages.
+    pub query: QueryBuilder,
+    /// The Dify input variable receiving the system messages.
+    pub system_input: Option<String>,
+    /// Validates the inputs against the variables declared by the app.
+    pub validate_inputs: bool,
+    /// How the workflow is run, for the workflow apps.
+    pub workflow: Option<WorkflowConfig>,
+    /// How the agent thoughts are sent to the clients.
+    pub reasoning: ReasoningMode,
+    /// The prompt describing the tools of the requests.
+    pub tools_prompt: String,
+}
+
+/// Routes the `model` of the requests to the Dify apps.
+#[derive(Clone, Debug, Default)]
+pub struct ModelRegistry {
+    /// The configured models, ordered by name.
+    routes: BTreeMap<String, ModelRoute>,
+    /// Serves the unknown model names, if configured.
+    fallback: Option<String>,
+}
+
+impl ModelRegistry {
+    /// Creates the registry from a validated config.
+    pub fn new(config: &Config) -> Self {
+        let routes = config
+            .models
+            .iter()
+            .filter_map(|(name, model)| {
+                let upstream_name = config.model_upstream(model)?;
+                let upstream = config.upstreams.get(upstream_name)?;
+                let api_key = model.api_key.as_ref().or(upstream.api_key.as_ref());
+                let client = DifyClient::new_with_config(DifyConfig {
+                    base_url: upstream.base_url.clone(),
+                    api_key: api_key.cloned().unwrap_or_default(),
+                    timeout: Duration::from_secs(upstream.timeout),
+                });
+                // a workflow has no conversation, it gets the last message unless its query says otherwise
+                let workflow = (model.app_type == AppMode::Workflow)
+                    .then(|| model.workflow.clone().unwrap_or_default());
+                let mut query = model.query.clone();
+                if workflow.is_some() && query.as_ref().and_then(|q| q.mode).is_none() {
+                    query = Some(QueryConfig {
+                        mode: Some(QueryMode::LastMessage),
+                        ..query.unwrap_or_default()
+                    });
+                }
+                let route = ModelRoute {
+                    name: name.clone(),
+                    upstream: upstream_name.to_owned(),
+                    client,
+                    app_type: model.app_type.clone(),
+                    query: QueryBuilder::new(query.as_ref(), &config.query),
+                    system_input: model.system_input.clone(),
+                    validate_inputs: model.validate_inputs,
+                    workflow,
+                    reasoning: model.reasoning,
+                    tools_prompt: model
+                        .tools
+                        .as_ref()
+                        .and_then(|t| t.prompt.clone())
+                        .or(config.tools.prompt.clone())
+                        .unwrap_or(tools::DEFAULT_PROMPT.into()),
+                };
+                Some((name.clone(), route))
+            })
+            .collect();
+        Self {
+            routes,
+            fallback: config.default_model.clone(),
+        }
+    }
+
+    /// Returns the route of a model, `None` if the model is unknown.
+    pub fn get(&self, model: &str) -> Option<&ModelRoute> {
+        self.routes
+            .get(model)
+            .or_else(|| self.routes.get(self.fallback.as_deref()?))
+    }
+
+    /// Returns the route of a configured model, ignoring the fallback.
+    pub fn get_exact(&self, model: &str) -> Option<&ModelRoute> {
+        self.routes.get(model)
+    }
+
+    /// Iterates over the configured models, ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = &ModelRoute> {
+        self.routes.values()
+    }
+}
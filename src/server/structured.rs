@@ -0,0 +1,205 @@
+//! Enforces the JSON `response_format` of the requests, which Dify doesn't support.
+//!
+//! The format is described to the app in the query, then the JSON is extracted from the answer,
+//! fenced or not, and validated against the schema of `json_schema`.
+use super::{helper::ApiError, query};
+use jsonschema::JSONSchema;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+/// The default prompt describing the format, with the `{format}` and `{query}` placeholders.
+pub const DEFAULT_PROMPT: &str = "{query}\n\n{format}";
+
+/// An object specifying the format that the model must output.
+#[derive(Debug, Deserialize)]
+pub struct ResponseFormat {
+    #[serde(rename = "type")]
+    type_: ResponseFormatType,
+    /// The schema of the `json_schema` type.
+    json_schema: Option<JsonSchemaFormat>,
+}
+
+/// The format that the model must output.
+/// Setting to { "type": "json_object" } enables JSON mode, which guarantees the message the model generates is valid JSON.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormatType {
+    JsonObject,
+    JsonSchema,
+    Text,
+}
+
+/// The schema of a structured output.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct JsonSchemaFormat {
+    /// The name of the response format.
+    name: String,
+    /// What the response format is for.
+    description: Option<String>,
+    /// The JSON schema of the response.
+    schema: Option<JsonValue>,
+    /// Whether to enable strict schema adherence, the schema is always enforced by the gateway.
+    strict: Option<bool>,
+}
+
+/// The JSON format of the answers of a request.
+pub struct JsonFormat {
+    /// The schema of `json_schema`, with its compiled validator.
+    schema: Option<(JsonValue, JSONSchema)>,
+}
+
+impl JsonFormat {
+    /// Reads the `response_format` of a request, `None` for text.
+    pub fn from_request(format: Option<ResponseFormat>) -> Result<Option<Self>, ApiError> {
+        let Some(format) = format else {
+            return Ok(None);
+        };
+        let schema = match format.type_ {
+            ResponseFormatType::Text => return Ok(None),
+            ResponseFormatType::JsonObject => None,
+            ResponseFormatType::JsonSchema => {
+                let param = "response_format.json_schema";
+                let Some(json_schema) = format.json_schema else {
+                    let message = "The `json_schema` type requires a `json_schema` object.";
+                    return Err(ApiError::invalid_request(message, Some(param)));
+                };
+                json_schema.schema
+            }
+        };
+        let schema = match schema {
+            Some(schema) => {
+                let validator = JSONSchema::compile(&schema).map_err(|e| {
+                    let message = format!("Invalid JSON schema: {e}");
+                    ApiError::invalid_request(message, Some("response_format.json_schema.schema"))
+                })?;
+                Some((schema, validator))
+            }
+            None => None,
+        };
+        Ok(Some(Self { schema }))
+    }
+
+    /// Describes the format to the model, around the query.
+    pub fn prompt(&self, template: &str, query_string: &str) -> String {
+        let mut format = "Answer with a valid JSON object only, without any other text.".to_owned();
+        if let Some((schema, _)) = self.schema.as_ref() {
+            format.push_str(&format!(" The JSON must match this JSON schema:\n{schema}"));
+        }
+        query::render(template, &[("format", &format), ("query", query_string)])
+    }
+
+    /// Returns the instructions to answer again, after an invalid answer.
+    pub fn feedback(error: &str) -> String {
+        format!("\n\nYour previous answer was not valid: {error}. Answer again with the JSON only.")
+    }
+
+    /// Extracts the JSON of an answer, from a fenced code block or around the outer braces,
+    /// and checks it against the schema.
+    pub fn extract<'a>(&self, answer: &'a str) -> Result<&'a str, String> {
+        let text = fenced(answer).unwrap_or(answer).trim();
+        let text = match serde_json::from_str::<JsonValue>(text) {
+            Ok(_) => text,
+            Err(_) => match (text.find('{'), text.rfind('}')) {
+                (Some(start), Some(end)) if start < end => &text[start..=end],
+                _ => text,
+            },
+        };
+        let value = serde_json::from_str::<JsonValue>(text)
+            .map_err(|e| format!("the answer is not JSON ({e})"))?;
+        if !value.is_object() {
+            return Err("the answer is not a JSON object".into());
+        }
+        if let Some((_, validator)) = self.schema.as_ref() {
+            if let Err(errors) = validator.validate(&value) {
+                let errors = errors
+                    .map(|e| format!("{} at `{}`", e, e.instance_path))
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                return Err(format!("the JSON doesn't match the schema: {errors}"));
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// Returns the content of the first fenced code block of a text.
+fn fenced(text: &str) -> Option<&str> {
+    let start = text.find("```")? + 3;
+    // skips the language of the block
+    let start = start + text[start..].find('\n')? + 1;
+    let end = start + text[start..].find("```")?;
+    Some(&text[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn format(schema: Option<JsonValue>) -> JsonFormat {
+        let format = json!({
+            "type": if schema.is_some() { "json_schema" } else { "json_object" },
+            "json_schema": schema.map(|schema| json!({"name": "answer", "schema": schema})),
+        });
+        let format = serde_json::from_value(format).unwrap();
+        JsonFormat::from_request(Some(format)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn fenced_block() {
+        let answer = "Here it is:\n```json\n{\"a\": 1}\n```\nAnything else?";
+        assert_eq!(format(None).extract(answer), Ok("{\"a\": 1}"));
+    }
+
+    #[test]
+    fn bare_json_with_prose() {
+        let answer = "Sure! {\"a\": {\"b\": [1, 2]}} Hope it helps.";
+        assert_eq!(format(None).extract(answer), Ok("{\"a\": {\"b\": [1, 2]}}"));
+        assert_eq!(format(None).extract(" {\"a\": 1}\n"), Ok("{\"a\": 1}"));
+    }
+
+    #[test]
+    fn invalid_json() {
+        let error = format(None).extract("{\"a\": 1,}").unwrap_err();
+        assert!(error.starts_with("the answer is not JSON"), "{error}");
+        let error = format(None).extract("no JSON here").unwrap_err();
+        assert!(error.starts_with("the answer is not JSON"), "{error}");
+        let error = format(None).extract("[1, 2]").unwrap_err();
+        assert_eq!(error, "the answer is not a JSON object");
+    }
+
+    #[test]
+    fn schema_violation() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}},
+            "required": ["city"],
+        });
+        let format = format(Some(schema));
+        assert_eq!(
+            format.extract("{\"city\": \"Paris\"}"),
+            Ok("{\"city\": \"Paris\"}")
+        );
+        let error = format.extract("{\"city\": 1}").unwrap_err();
+        assert!(
+            error.starts_with("the JSON doesn't match the schema"),
+            "{error}"
+        );
+        assert!(error.contains("`/city`"), "{error}");
+    }
+
+    #[test]
+    fn invalid_schema() {
+        let format = json!({
+            "type": "json_schema",
+            "json_schema": {"name": "answer", "schema": {"type": 1}},
+        });
+        let format = serde_json::from_value(format).unwrap();
+        let err = JsonFormat::from_request(Some(format)).err().unwrap();
+        assert_eq!(
+            err.param.as_deref(),
+            Some("response_format.json_schema.schema")
+        );
+    }
+}
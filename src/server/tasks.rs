@@ -0,0 +1,160 @@
+//! Tracks the Dify tasks of the streamed answers, to stop them when they are no longer wanted.
+//!
+//! Dify keeps generating an answer after the client is gone, so the tasks of a stream are
+//! stopped when its body is dropped before they finish. They are also registered by completion
+//! id while running, for an explicit cancellation.
+use super::{dify, registry::ModelRoute};
+use dify_client::{response::AppMode, Client as DifyClient};
+use opentelemetry::trace::FutureExt;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Stops the Dify tasks of a route, in the background.
+/// Dify only stops the tasks of the user who started them.
+#[derive(Clone)]
+pub struct TaskStopper {
+    client: DifyClient,
+    app_type: AppMode,
+    token: Option<String>,
+    user: String,
+}
+
+impl TaskStopper {
+    /// Creates a stopper for the tasks started on a route, with the token and user of the request.
+    pub fn new(route: &ModelRoute, token: Option<&str>, user: &str) -> Self {
+        Self {
+            client: route.client.clone(),
+            app_type: route.app_type.clone(),
+            token: token.map(String::from),
+            user: user.to_owned(),
+        }
+    }
+
+    /// Stops a task, the errors are only logged.
+    /// The call is traced within the current request, if any.
+    pub fn stop(&self, task_id: &str) {
+        let stopper = self.clone();
+        let task_id = task_id.to_owned();
+        tokio::spawn(
+            async move {
+                let token = stopper.token.as_deref();
+                let (client, app_type, user) = (&stopper.client, &stopper.app_type, &stopper.user);
+                if let Err(e) = dify::stop_task(client, token, app_type, &task_id, user).await {
+                    log::warn!("Failed to stop the Dify task {}: {}", task_id, e);
+                }
+            }
+            .with_current_context(),
+        );
+    }
+}
+
+struct RunningTask {
+    task_id: String,
+    /// The key id of the client which started the task, only this client may cancel it.
+    owner: Option<String>,
+    stopper: TaskStopper,
+}
+
+/// The running Dify tasks, by completion id.
+#[derive(Default)]
+pub struct TaskRegistry {
+    tasks: Mutex<HashMap<String, Vec<RunningTask>>>,
+}
+
+impl TaskRegistry {
+    fn insert(&self, id: &str, task: RunningTask) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.entry(id.to_owned()).or_default().push(task);
+    }
+
+    fn remove(&self, id: &str, task_id: &str) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if let Some(running) = tasks.get_mut(id) {
+            running.retain(|task| task.task_id != task_id);
+            if running.is_empty() {
+                tasks.remove(id);
+            }
+        }
+    }
+
+    /// Stops the tasks of a completion started by the same client.
+    /// Returns `false` if the completion isn't running.
+    pub fn cancel(&self, id: &str, owner: Option<&str>) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        let Some(running) = tasks.get_mut(id) else {
+            return false;
+        };
+        let (cancelled, kept) = std::mem::take(running)
+            .into_iter()
+            .partition::<Vec<_>, _>(|task| task.owner.as_deref() == owner);
+        *running = kept;
+        if running.is_empty() {
+            tasks.remove(id);
+        }
+        for task in cancelled.iter() {
+            log::info!("Cancelling the Dify task {} of {}", task.task_id, id);
+            task.stopper.stop(&task.task_id);
+        }
+        !cancelled.is_empty()
+    }
+}
+
+/// Guards the tasks of a streamed completion, by choice.
+/// The tasks still running when the guard is dropped are stopped.
+pub struct TaskGuard {
+    registry: Arc<TaskRegistry>,
+    owner: Option<String>,
+    stopper: TaskStopper,
+    /// The completion id and the running task of the choices.
+    running: HashMap<usize, (String, String)>,
+}
+
+impl TaskGuard {
+    /// Creates a guard for the tasks started with a stopper, by the client of the given key id.
+    pub fn new(registry: Arc<TaskRegistry>, stopper: TaskStopper, owner: Option<String>) -> Self {
+        Self {
+            registry,
+            owner,
+            stopper,
+            running: HashMap::new(),
+        }
+    }
+
+    /// Records the task of a choice, once started. The replayed answers have no task.
+    pub fn started(&mut self, index: usize, id: &str, task_id: &str) {
+        if task_id.is_empty() {
+            return;
+        }
+        let task = RunningTask {
+            task_id: task_id.to_owned(),
+            owner: self.owner.clone(),
+            stopper: self.stopper.clone(),
+        };
+        self.registry.insert(id, task);
+        self.running
+            .insert(index, (id.to_owned(), task_id.to_owned()));
+    }
+
+    /// Forgets the task of a choice, once its answer ended.
+    pub fn finished(&mut self, index: usize) {
+        if let Some((id, task_id)) = self.running.remove(&index) {
+            self.registry.remove(&id, &task_id);
+        }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        for (_, (id, task_id)) in self.running.drain() {
+            log::info!(
+                "Stopping the Dify task {} of {}, the stream was dropped",
+                task_id,
+                id
+            );
+            self.registry.remove(&id, &task_id);
+            self.stopper.stop(&task_id);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Traces the requests with OpenTelemetry, the spans are exported over OTLP when configured.
+//!
+//! A request gets a server span, continuing the W3C trace context of the client if any, with
+//! child spans for the query building and each Dify API call. The trace context is sent to Dify
+//! in the `traceparent` header of the calls, so a traced Dify joins the same trace.
+use super::answer::TokenUsage;
+use crate::config::TracingConfig;
+use anyhow::Context as _;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use dify_client::response::{AppMode, ErrorResponse};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace::TracerProvider};
+
+/// The name of the tracer of the gateway spans.
+const TRACER: &str = "dify-openai-apis";
+
+/// Sets up the trace context propagation, and the export of the spans if an endpoint is set.
+/// Must be called within the Tokio runtime.
+pub fn init_tracing(config: &TracingConfig) -> anyhow::Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let Some(endpoint) = config.otlp_endpoint.as_deref() else {
+        return Ok(());
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .context("failed to create the OTLP exporter")?;
+    let resource = opentelemetry_sdk::Resource::new([KeyValue::new(
+        "service.name",
+        config.service_name.clone(),
+    )]);
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(resource)
+        .build();
+    global::set_tracer_provider(provider);
+    Ok(())
+}
+
+/// Reads the trace context from the headers of a request.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Writes the trace context to the headers of a request.
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Returns the trace context headers of a context, `traceparent` and `tracestate`.
+pub fn headers(cx: &Context) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(cx, &mut HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+/// Traces a request with a server span, continuing the trace of the client if any.
+pub async fn trace(req: Request, next: Next) -> Response {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| req.uri().path().to_owned());
+    let method = req.method().to_string();
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(format!("{method} {route}"))
+        .with_kind(SpanKind::Server)
+        .with_attributes([
+            KeyValue::new("http.request.method", method),
+            KeyValue::new("http.route", route),
+        ])
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+    let response = next.run(req).with_context(cx.clone()).await;
+    cx.span().set_attribute(KeyValue::new(
+        "http.response.status_code",
+        i64::from(response.status().as_u16()),
+    ));
+    response
+}
+
+/// The model of a request, set on the spans of its Dify calls.
+struct ModelAttributes(Vec<KeyValue>);
+
+/// Sets the model of a request on the current span.
+/// Returns the context to run the request in, for the spans of its Dify calls.
+pub fn with_model(requested: &str, route: Option<(&str, &AppMode)>) -> Context {
+    let mut attributes = vec![KeyValue::new("gen_ai.request.model", requested.to_owned())];
+    if let Some((model, app_type)) = route {
+        attributes.push(KeyValue::new("dify.model", model.to_owned()));
+        let app_type = serde_json::to_value(app_type)
+            .ok()
+            .and_then(|value| value.as_str().map(String::from))
+            .unwrap_or_default();
+        attributes.push(KeyValue::new("dify.app_type", app_type));
+    }
+    let cx = Context::current();
+    cx.span().set_attributes(attributes.clone());
+    cx.with_value(ModelAttributes(attributes))
+}
+
+/// Starts a span, child of the current one.
+pub fn span(name: &'static str) -> Context {
+    let tracer = global::tracer(TRACER);
+    Context::current_with_span(tracer.start(name))
+}
+
+/// Starts the client span of a Dify API call, with the model of the request.
+/// The `route` is the path of the call, without its ids.
+pub fn dify_span(method: &'static str, route: &str) -> Context {
+    let parent = Context::current();
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method),
+        KeyValue::new("url.template", route.to_owned()),
+    ];
+    if let Some(model) = parent.get::<ModelAttributes>() {
+        attributes.extend(model.0.iter().cloned());
+    }
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(format!("dify {method} {route}"))
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    parent.with_span(span)
+}
+
+/// Records the HTTP status of a Dify API call.
+pub fn record_status(cx: &Context, status: u16) {
+    cx.span().set_attribute(KeyValue::new(
+        "http.response.status_code",
+        i64::from(status),
+    ));
+}
+
+/// Records the Dify conversation of an answer.
+pub fn record_conversation(cx: &Context, conversation_id: &str) {
+    if !conversation_id.is_empty() {
+        cx.span().set_attribute(KeyValue::new(
+            "dify.conversation_id",
+            conversation_id.to_owned(),
+        ));
+    }
+}
+
+/// Records the tokens of an answer.
+pub fn record_usage(cx: &Context, usage: TokenUsage) {
+    cx.span().set_attributes([
+        KeyValue::new("gen_ai.usage.input_tokens", usage.prompt_tokens as i64),
+        KeyValue::new("gen_ai.usage.output_tokens", usage.completion_tokens as i64),
+        KeyValue::new("dify.usage.total_tokens", usage.total_tokens as i64),
+    ]);
+}
+
+/// Records an error, by type. The span is marked as failed if there is a message.
+pub fn record_error(cx: &Context, error_type: &str, message: Option<&str>) {
+    let span = cx.span();
+    span.set_attribute(KeyValue::new("error.type", error_type.to_owned()));
+    if let Some(message) = message {
+        span.set_status(Status::error(message.to_owned()));
+    }
+}
+
+/// Records the failure of a Dify API call.
+pub fn record_failure(cx: &Context, err: &anyhow::Error) {
+    if let Some(err) = err.downcast_ref::<ErrorResponse>() {
+        record_status(cx, err.status as u16);
+        record_error(cx, &err.code, Some(&err.message));
+    } else if let Some(e) = err.downcast_ref::<reqwest::Error>() {
+        let error_type = if e.is_timeout() {
+            "timeout"
+        } else {
+            "upstream_unavailable"
+        };
+        record_error(cx, error_type, Some(&e.to_string()));
+    } else {
+        record_error(cx, "error", Some(&format!("{err:#}")));
+    }
+}
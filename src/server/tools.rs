@@ -0,0 +1,432 @@
+//! Emulates the OpenAI tool calling on top of the Dify apps.
+//!
+//! The tools of a request are described to the app in the query, with a prompt asking it to answer
+//! with `<tool_call>` blocks, which are parsed back into the `tool_calls` of the answer.
+//! The previous calls and the `tool` results are rendered in the same tags, so the history stays
+//! consistent across turns.
+use super::{helper::ApiError, query};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// The default prompt describing the tools, with the `{tools}`, `{tool_choice}` and `{query}` placeholders.
+pub const DEFAULT_PROMPT: &str = r#"You can call the following tools, described by their JSON schemas:
+{tools}
+
+{tool_choice}
+To call a tool, answer with a <tool_call></tool_call> block holding a JSON object with the `name` of the tool and its `arguments`, e.g.:
+<tool_call>{"name": "get_weather", "arguments": {"city": "Paris"}}</tool_call>
+Use one block per call. The results are given back in <tool_response></tool_response> blocks.
+
+{query}"#;
+
+const CALL_START: &str = "<tool_call>";
+const CALL_END: &str = "</tool_call>";
+const RESPONSE_START: &str = "<tool_response>";
+const RESPONSE_END: &str = "</tool_response>";
+
+/// A tool the model may call, only functions are supported.
+#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+pub struct Tool {
+    /// The type of the tool, always `function`.
+    #[serde(rename = "type")]
+    type_: String,
+    /// The function definition.
+    function: FunctionDefinition,
+}
+
+/// A function the model may call.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FunctionDefinition {
+    /// The name of the function.
+    name: String,
+    /// What the function does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// The parameters of the function, as a JSON schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<JsonValue>,
+}
+
+/// A tool call of the model.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    /// The id of the call, referenced by the `tool` message of its result.
+    pub id: String,
+    /// The type of the tool, always `function`.
+    #[serde(rename = "type", default = "function_type")]
+    pub type_: String,
+    /// The function called by the model.
+    pub function: FunctionCall,
+}
+
+fn function_type() -> String {
+    "function".into()
+}
+
+/// A function call, with its arguments as a JSON string.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FunctionCall {
+    /// The name of the function.
+    pub name: String,
+    /// The arguments of the call, as a JSON object encoded in a string.
+    #[serde(default)]
+    pub arguments: String,
+}
+
+/// Which tools the model should call.
+#[derive(Debug, PartialEq)]
+enum ToolChoice {
+    /// The model picks between answering and calling tools.
+    Auto,
+    /// The model must call one or more tools.
+    Required,
+    /// The model must call the given function.
+    Function(String),
+}
+
+/// The tools of a chat request.
+#[derive(Debug)]
+pub struct ToolSet {
+    functions: Vec<FunctionDefinition>,
+    choice: ToolChoice,
+    /// The deprecated `functions` were given, the call is returned as a `function_call`.
+    pub legacy: bool,
+}
+
+impl ToolSet {
+    /// Reads the tools of a request, from `tools` and `tool_choice`,
+    /// or the deprecated `functions` and `function_call`.
+    /// Returns `None` without tools, or when the model must not call them.
+    pub fn from_request(
+        tools: Option<Vec<Tool>>,
+        tool_choice: Option<JsonValue>,
+        functions: Option<Vec<FunctionDefinition>>,
+        function_call: Option<JsonValue>,
+    ) -> Result<Option<Self>, ApiError> {
+        let (functions, choice, param, legacy) = match (tools, functions) {
+            (Some(tools), _) => {
+                let functions = tools.into_iter().map(|tool| tool.function).collect();
+                (functions, tool_choice, "tool_choice", false)
+            }
+            (None, Some(functions)) => (functions, function_call, "function_call", true),
+            (None, None) => return Ok(None),
+        };
+        if functions.is_empty() {
+            return Ok(None);
+        }
+        let choice = match choice {
+            None => ToolChoice::Auto,
+            Some(JsonValue::String(mode)) => match mode.as_str() {
+                "none" => return Ok(None),
+                "auto" => ToolChoice::Auto,
+                "required" if !legacy => ToolChoice::Required,
+                _ => {
+                    let message = format!("Invalid value `{mode}` of `{param}`.");
+                    return Err(ApiError::invalid_request(message, Some(param)));
+                }
+            },
+            Some(choice) => {
+                // `{"type": "function", "function": {"name": ...}}`, or `{"name": ...}` for `function_call`
+                let function = choice.get("function").unwrap_or(&choice);
+                let Some(name) = function.get("name").and_then(|n| n.as_str()) else {
+                    let message = format!("The `{param}` object must name a function.");
+                    return Err(ApiError::invalid_request(message, Some(param)));
+                };
+                if !functions.iter().any(|f| f.name == name) {
+                    let message = format!("The `{param}` names an unknown function `{name}`.");
+                    return Err(ApiError::invalid_request(message, Some(param)));
+                }
+                ToolChoice::Function(name.to_owned())
+            }
+        };
+        Ok(Some(Self {
+            functions,
+            choice,
+            legacy,
+        }))
+    }
+
+    /// Describes the tools to the model, around the query.
+    pub fn prompt(&self, template: &str, query_string: &str) -> String {
+        let tools = self
+            .functions
+            .iter()
+            .map(|function| serde_json::to_string(function).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let choice = match &self.choice {
+            ToolChoice::Auto => {
+                "Call the tools when they help to answer, otherwise answer directly.".into()
+            }
+            ToolChoice::Required => "You must call at least one tool.".into(),
+            ToolChoice::Function(name) => format!("You must call the tool `{name}`."),
+        };
+        query::render(
+            template,
+            &[
+                ("tools", &tools),
+                ("tool_choice", &choice),
+                ("query", query_string),
+            ],
+        )
+    }
+}
+
+/// Renders the calls of an assistant message after its content, as the model writes them.
+pub fn render_calls(content: &str, calls: &[ToolCall]) -> String {
+    let mut text = content.to_owned();
+    for call in calls {
+        let arguments = serde_json::from_str::<JsonValue>(&call.function.arguments)
+            .unwrap_or_else(|_| call.function.arguments.clone().into());
+        let mut rendered = serde_json::Map::new();
+        if !call.id.is_empty() {
+            rendered.insert("id".into(), call.id.clone().into());
+        }
+        rendered.insert("name".into(), call.function.name.clone().into());
+        rendered.insert("arguments".into(), arguments);
+        let call = JsonValue::Object(rendered);
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(&format!("{CALL_START}{call}{CALL_END}"));
+    }
+    text
+}
+
+/// Renders the result of a tool call, given in a `tool` or `function` message.
+pub fn render_response(id: Option<&str>, name: Option<&str>, content: &str) -> String {
+    let mut response = serde_json::Map::new();
+    if let Some(id) = id {
+        response.insert("id".into(), id.into());
+    }
+    if let Some(name) = name {
+        response.insert("name".into(), name.into());
+    }
+    response.insert("content".into(), content.into());
+    format!(
+        "{RESPONSE_START}{}{RESPONSE_END}",
+        JsonValue::Object(response)
+    )
+}
+
+/// A part of an answer, either text or a tool call.
+#[derive(Debug)]
+pub enum AnswerPart {
+    Text(String),
+    Call(ToolCall),
+}
+
+/// Parses the tool calls out of an answer, as it is streamed.
+/// The text which may start a call is held back until it is known.
+pub struct ToolCallParser {
+    /// The id of the answer, the call ids are derived from it.
+    answer_id: String,
+    pending: String,
+    calls: usize,
+}
+
+impl ToolCallParser {
+    /// Creates a parser for the answer of the given id.
+    pub fn new(answer_id: &str) -> Self {
+        Self {
+            answer_id: answer_id.to_owned(),
+            pending: String::new(),
+            calls: 0,
+        }
+    }
+
+    /// Sets the id of the answer, known once it starts.
+    pub fn set_answer_id(&mut self, answer_id: &str) {
+        answer_id.clone_into(&mut self.answer_id);
+    }
+
+    /// Pushes a delta of the answer, returns the parts known so far.
+    pub fn push(&mut self, delta: &str) -> Vec<AnswerPart> {
+        self.pending.push_str(delta);
+        let mut parts = Vec::new();
+        loop {
+            let Some(start) = self.pending.find(CALL_START) else {
+                // keeps the end which may start a call
+                let keep = (1..CALL_START.len())
+                    .rev()
+                    .find(|&n| self.pending.ends_with(&CALL_START[..n]))
+                    .unwrap_or(0);
+                let text = self.pending[..self.pending.len() - keep].to_owned();
+                self.pending.drain(..text.len());
+                self.text(text, &mut parts);
+                break;
+            };
+            let Some(end) = self.pending[start..].find(CALL_END) else {
+                let text = self.pending[..start].to_owned();
+                self.pending.drain(..start);
+                self.text(text, &mut parts);
+                break;
+            };
+            let end = start + end + CALL_END.len();
+            let block = self.pending[start..end].to_owned();
+            let text = self.pending[..start].to_owned();
+            self.pending.drain(..end);
+            self.text(text, &mut parts);
+            let body = &block[CALL_START.len()..block.len() - CALL_END.len()];
+            match self.call(body) {
+                Some(call) => parts.push(AnswerPart::Call(call)),
+                None => self.text(block, &mut parts),
+            }
+        }
+        parts
+    }
+
+    /// Ends the answer, an unterminated call is returned as text.
+    pub fn finish(&mut self) -> Vec<AnswerPart> {
+        let mut parts = Vec::new();
+        let text = std::mem::take(&mut self.pending);
+        self.text(text, &mut parts);
+        parts
+    }
+
+    /// Adds a text part, the whitespace around the calls is dropped.
+    fn text(&self, text: String, parts: &mut Vec<AnswerPart>) {
+        let blank = text.trim().is_empty() && self.calls > 0;
+        if !text.is_empty() && !blank {
+            parts.push(AnswerPart::Text(text));
+        }
+    }
+
+    /// Parses the JSON body of a call, `{"name", "arguments"}`.
+    fn call(&mut self, body: &str) -> Option<ToolCall> {
+        let call: JsonValue = serde_json::from_str(body.trim()).ok()?;
+        let name = call.get("name")?.as_str()?.to_owned();
+        let arguments = match call.get("arguments") {
+            None | Some(JsonValue::Null) => "{}".into(),
+            Some(JsonValue::String(arguments)) => arguments.clone(),
+            Some(arguments) => arguments.to_string(),
+        };
+        let mut hasher = DefaultHasher::new();
+        (self.answer_id.as_str(), self.calls).hash(&mut hasher);
+        self.calls += 1;
+        Some(ToolCall {
+            id: format!("call_{:016x}", hasher.finish()),
+            type_: function_type(),
+            function: FunctionCall { name, arguments },
+        })
+    }
+}
+
+/// Parses the tool calls out of a whole answer, returns its text and calls.
+pub fn parse(answer_id: &str, answer: &str) -> (String, Vec<ToolCall>) {
+    let mut parser = ToolCallParser::new(answer_id);
+    let mut parts = parser.push(answer);
+    parts.extend(parser.finish());
+    let mut text = String::new();
+    let mut calls = Vec::new();
+    for part in parts {
+        match part {
+            AnswerPart::Text(part) => text.push_str(&part),
+            AnswerPart::Call(call) => calls.push(call),
+        }
+    }
+    let text = match calls.is_empty() {
+        true => text,
+        false => text.trim().to_owned(),
+    };
+    (text, calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Streams the answer in chunks, returns the text and the calls.
+    fn stream(chunks: &[&str]) -> (String, Vec<ToolCall>) {
+        let mut parser = ToolCallParser::new("msg-1");
+        let mut parts = Vec::new();
+        for chunk in chunks {
+            parts.extend(parser.push(chunk));
+        }
+        parts.extend(parser.finish());
+        let mut text = String::new();
+        let mut calls = Vec::new();
+        for part in parts {
+            match part {
+                AnswerPart::Text(part) => text.push_str(&part),
+                AnswerPart::Call(call) => calls.push(call),
+            }
+        }
+        (text, calls)
+    }
+
+    #[test]
+    fn call_split_across_chunks() {
+        let (text, calls) = stream(&[
+            "<tool",
+            "_call>{\"name\": \"get_weather\", ",
+            "\"arguments\": {\"city\": \"Paris\"}}</tool_",
+            "call>",
+        ]);
+        assert_eq!(text, "");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        assert!(calls[0].id.starts_with("call_"));
+    }
+
+    #[test]
+    fn text_is_not_held_back() {
+        let mut parser = ToolCallParser::new("msg-1");
+        let parts = parser.push("a < b <tool");
+        assert!(matches!(&parts[..], [AnswerPart::Text(text)] if text == "a < b "));
+        let parts = parser.push(" not a call");
+        assert!(matches!(&parts[..], [AnswerPart::Text(text)] if text == "<tool not a call"));
+    }
+
+    #[test]
+    fn multiple_calls_with_text_around() {
+        let answer = "Let me check.\n\
+            <tool_call>{\"name\": \"a\", \"arguments\": \"{\\\"x\\\": 1}\"}</tool_call>\n\
+            <tool_call>{\"name\": \"b\"}</tool_call>\nDone.";
+        let (text, calls) = parse("msg-1", answer);
+        assert_eq!(text, "Let me check.\n\nDone.");
+        let names = calls
+            .iter()
+            .map(|c| c.function.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(calls[0].function.arguments, r#"{"x": 1}"#);
+        assert_eq!(calls[1].function.arguments, "{}");
+        assert_ne!(calls[0].id, calls[1].id);
+        // the ids are derived from the answer id
+        assert_eq!(parse("msg-1", answer).1[0].id, calls[0].id);
+    }
+
+    #[test]
+    fn malformed_calls_are_text() {
+        let answer = "<tool_call>{\"name\": \"a\", </tool_call>";
+        let (text, calls) = parse("msg-1", answer);
+        assert_eq!(text, answer);
+        assert!(calls.is_empty());
+        let answer = "<tool_call>{\"arguments\": {}}</tool_call>";
+        assert_eq!(parse("msg-1", answer).0, answer);
+        // an unterminated call is text once the answer ends
+        let (text, calls) = stream(&["<tool_call>{\"name\": \"a\"}"]);
+        assert_eq!(text, "<tool_call>{\"name\": \"a\"}");
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn render_calls_round_trip() {
+        let (_, calls) = parse(
+            "msg-1",
+            r#"<tool_call>{"name": "a", "arguments": {"x": 1}}</tool_call>"#,
+        );
+        let rendered = render_calls("Sure.", &calls);
+        let (text, parsed) = parse("msg-2", &rendered);
+        assert_eq!(text, "Sure.");
+        assert_eq!(parsed[0].function.name, "a");
+        assert_eq!(parsed[0].function.arguments, r#"{"x":1}"#);
+    }
+}
@@ -1,9 +1,32 @@
-use std::time::Duration;
+use std::{borrow::Cow, collections::HashMap, future::Future, sync::Arc, time::Duration};
 
-use super::helper::*;
+use super::{
+    access_log::{AccessLog, Content},
+    answer::{
+        strip_think, workflow_output, Answer, AnswerEvent, AnswerEvents, AnswerLimits, TokenUsage,
+        THINK_END, THINK_START,
+    },
+    audit,
+    auth::Client,
+    conversation::{ConversationRecorder, Fingerprint},
+    dify, files,
+    helper::*,
+    inputs::{merge_request_inputs, ParametersCache},
+    metrics::{self, StreamMetrics},
+    query,
+    rate_limits::{Lease, RateLimited},
+    registry::ModelRoute,
+    structured::{self, JsonFormat, ResponseFormat},
+    tasks::{TaskGuard, TaskStopper},
+    telemetry,
+    tools::{
+        self, AnswerPart, FunctionCall, FunctionDefinition, Tool, ToolCall, ToolCallParser, ToolSet,
+    },
+};
+use crate::config::{ReasoningMode, WorkflowConfig};
 use anyhow::{anyhow, Error as AnyError};
 use axum::{
-    extract::{Json, Request, State},
+    extract::{Extension, Json, Path, Request, State},
     http::{HeaderMap, Method, StatusCode},
     middleware::Next,
     response::{
@@ -14,10 +37,14 @@ use axum::{
 use dify_client::{
     api::Api,
     http::{header, Request as HttpRequest},
-    request::ChatMessagesRequest,
-    response::{ErrorResponse, SseMessageEvent},
+    request::{ChatMessagesRequest, CompletionMessagesRequest, WorkflowsRunRequest},
+    response::{AppMode, ErrorResponse},
+};
+use futures::{stream, Stream};
+use opentelemetry::{
+    trace::{FutureExt, TraceContextExt},
+    Context, KeyValue,
 };
-use futures::stream;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use tokio_stream::StreamExt;
@@ -60,9 +87,13 @@ pub struct ChatCompletionRequest {
     /// An integer between 0 and 20 specifying the number of most likely tokens to return at each token position, each with an associated log probability. logprobs must be set to true if this parameter is used.
     /// Default: 0
     top_logprobs: Option<u64>,
+    /// Deprecated in favor of `max_completion_tokens`.
     /// The maximum number of tokens that can be generated in the chat completion.
     /// The total length of input tokens and generated tokens is limited by the model's context length.
     max_tokens: Option<u64>,
+    /// An upper bound for the number of tokens that can be generated for a completion.
+    /// The answer is cut by the gateway, which counts the tokens approximately.
+    max_completion_tokens: Option<u64>,
     /// How many chat completion choices to generate for each input message. Note that you will be charged based on the number of generated tokens across all of the choices. Keep n as 1 to minimize costs.
     /// Default: 1
     n: Option<u64>,
@@ -77,8 +108,9 @@ pub struct ChatCompletionRequest {
     /// If specified, our system will make a best effort to sample deterministically, such that repeated requests with the same seed and parameters should return the same result. Determinism is not guaranteed, and you should refer to the system_fingerprint response parameter to monitor changes in the backend.
     /// Default: null
     seed: Option<u64>,
-    /// Up to 4 sequences where the API will stop generating further tokens.
-    stop: Option<String>,
+    /// Up to 4 sequences where the API will stop generating further tokens, a string or an array.
+    /// The returned text will not contain the stop sequence.
+    stop: Option<StringOrArray>,
     /// If set, partial message deltas will be sent, like in ChatGPT. Tokens will be sent as data-only server-sent events as they become available, with the stream terminated by a data: [DONE] message.
     /// Default: false
     stream: Option<bool>,
@@ -92,7 +124,7 @@ pub struct ChatCompletionRequest {
     /// Default: 1.0
     top_p: Option<f64>,
     /// A list of tools the model may call. Currently, only functions are supported as a tool. Use this to provide a list of functions the model may generate JSON inputs for. A max of 128 functions are supported.
-    tools: Option<JsonValue>,
+    tools: Option<Vec<Tool>>,
     /// Controls which (if any) tool is called by the model. none means the model will not call any tool and instead generates a message. auto means the model can pick between generating a message or calling one or more tools. required means the model must call one or more tools. Specifying a particular tool via {"type": "function", "function": {"name": "my_function"}} forces the model to call that tool.
     /// none is the default when no tools are present. auto is the default if tools are present.
     tool_choice: Option<JsonValue>,
@@ -104,43 +136,232 @@ pub struct ChatCompletionRequest {
     function_call: Option<JsonValue>,
     /// Deprecated in favor of tools.
     /// A list of functions the model may generate JSON inputs for.
-    functions: Option<JsonValue>,
+    functions: Option<Vec<FunctionDefinition>>,
+    /// Developer-defined tags and values, forwarded as Dify inputs.
+    /// `conversation_id` continues the given Dify conversation, like the `X-Dify-Conversation-Id` header.
+    metadata: Option<HashMap<String, JsonValue>>,
+    /// The values of the Dify app input variables, e.g. set by `extra_body`. They take precedence over `metadata`.
+    inputs: Option<HashMap<String, JsonValue>>,
 }
 
-/// An object specifying the format that the model must output.
+/// The legacy completion request.
 #[allow(dead_code)]
-#[derive(Debug, Deserialize)]
-pub struct ResponseFormat {
-    #[serde(rename = "type")]
-    type_: ResponseFormatType,
+#[derive(Deserialize, Debug)]
+pub struct CompletionRequest {
+    /// ID of the model to use.
+    model: String,
+    /// The prompt(s) to generate completions for, a string or an array of strings.
+    /// Each prompt is sent to Dify in the `query` input variable.
+    prompt: StringOrArray,
+    /// The suffix that comes after a completion of inserted text, sent in the `suffix` input variable.
+    suffix: Option<String>,
+    /// The maximum number of tokens that can be generated in the completion.
+    max_tokens: Option<u64>,
+    /// What sampling temperature to use, between 0 and 2.
+    temperature: Option<f64>,
+    /// An alternative to sampling with temperature, called nucleus sampling.
+    top_p: Option<f64>,
+    /// How many completions to generate for each prompt.
+    n: Option<u64>,
+    /// Whether to stream back partial progress.
+    stream: Option<bool>,
+    /// Options for streaming response. Only set this when you set stream: true.
+    stream_options: Option<StreamOptions>,
+    /// Include the log probabilities on the most likely output tokens.
+    logprobs: Option<u64>,
+    /// Echo back the prompt in addition to the completion.
+    echo: Option<bool>,
+    /// Up to 4 sequences where the API will stop generating further tokens.
+    /// The returned text will not contain the stop sequence.
+    stop: Option<StringOrArray>,
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far.
+    presence_penalty: Option<f64>,
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing frequency in the text so far.
+    frequency_penalty: Option<f64>,
+    /// Generates best_of completions server-side and returns the "best".
+    best_of: Option<u64>,
+    /// Modify the likelihood of specified tokens appearing in the completion.
+    logit_bias: Option<JsonValue>,
+    /// A unique identifier representing your end-user.
+    user: Option<String>,
+    /// If specified, our system will make a best effort to sample deterministically.
+    seed: Option<u64>,
+    /// Developer-defined tags and values, forwarded as Dify inputs.
+    metadata: Option<HashMap<String, JsonValue>>,
+    /// The values of the Dify app input variables, e.g. set by `extra_body`. They take precedence over `metadata`.
+    inputs: Option<HashMap<String, JsonValue>>,
 }
 
-/// The format that the model must output.
-/// Setting to { "type": "json_object" } enables JSON mode, which guarantees the message the model generates is valid JSON.
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum ResponseFormatType {
-    JsonObject,
-    Text,
+/// A string or an array of strings.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum StringOrArray {
+    String(String),
+    Array(Vec<String>),
+}
+
+impl StringOrArray {
+    /// Returns the strings.
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::String(s) => vec![s],
+            Self::Array(a) => a,
+        }
+    }
 }
 
 /// Options for streaming response.
-#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct StreamOptions {
+    /// Whether to send the usage of the whole request in a last chunk, with no choices.
     include_usage: Option<bool>,
 }
 
+impl StreamOptions {
+    /// Returns whether the streamed usage is requested.
+    fn include_usage(options: Option<&Self>) -> bool {
+        options.and_then(|options| options.include_usage) == Some(true)
+    }
+}
+
 /// A message in the conversation.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Message {
     /// The role of the message.
     role: Role,
-    /// The content of the message.
-    content: String,
+    /// The content of the message, `null` for the tool calls of the assistant.
+    #[serde(default, deserialize_with = "nullable_content")]
+    content: MessageContent,
+    /// The reasoning of the assistant, the thoughts and tool observations of a Dify agent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+    /// The name of the function of a `function` message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The tool calls of the assistant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    /// The tool call answered by a `tool` message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    /// Deprecated in favor of `tool_calls`, the function call of the assistant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCall>,
+}
+
+impl Message {
+    /// Returns the text of the message, as sent to Dify.
+    /// The `<think>` block of an answer is left out, the tool calls and results are rendered in tags.
+    fn text(&self) -> Cow<'_, str> {
+        let text = self.content.text();
+        match self.role {
+            Role::Assistant => {
+                let text = match strip_think(&text) {
+                    stripped if stripped.len() == text.len() => text,
+                    stripped => Cow::Owned(stripped.to_owned()),
+                };
+                let legacy_call = self.function_call.clone().map(|function| ToolCall {
+                    id: String::new(),
+                    type_: "function".into(),
+                    function,
+                });
+                let calls = self.tool_calls.clone().into_iter().flatten();
+                let calls = calls.chain(legacy_call).collect::<Vec<_>>();
+                match calls.is_empty() {
+                    true => text,
+                    false => tools::render_calls(&text, &calls).into(),
+                }
+            }
+            Role::Tool | Role::Function => {
+                let id = self.tool_call_id.as_deref();
+                tools::render_response(id, self.name.as_deref(), &text).into()
+            }
+            _ => text,
+        }
+    }
+}
+
+/// Deserializes a message content, `null` being an empty text.
+fn nullable_content<'de, D>(deserializer: D) -> Result<MessageContent, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let content = Option::<MessageContent>::deserialize(deserializer)?;
+    Ok(content.unwrap_or_default())
+}
+
+/// The content of a message, either a text or an array of content parts.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, strum::Display)]
+impl MessageContent {
+    /// Returns the text of the content, the text parts are joined by new lines.
+    fn text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Text(text) => Cow::Borrowed(text),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+                .into(),
+        }
+    }
+
+    /// Returns the URLs of the image parts.
+    fn image_urls(&self) -> impl Iterator<Item = &str> {
+        let parts = match self {
+            Self::Text(_) => &[][..],
+            Self::Parts(parts) => parts.as_slice(),
+        };
+        parts.iter().filter_map(|part| match part {
+            ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+            ContentPart::Text { .. } => None,
+        })
+    }
+}
+
+/// A content part of a message.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    /// A text part.
+    Text { text: String },
+    /// An image part, the image is passed to Dify as a file.
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// The image of a content part.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageUrl {
+    /// Either a URL of the image or the base64 encoded image data, as a `data:` URI.
+    url: String,
+    /// The detail level of the image, ignored by Dify.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, strum::Display, strum::AsRefStr)]
 #[strum(serialize_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
 pub enum Role {
@@ -180,6 +401,12 @@ pub enum ObjectKind {
     ChatCompletion,
     #[serde(rename = "chat.completion.chunk")]
     ChatCompletionChunk,
+    #[serde(rename = "list")]
+    List,
+    #[serde(rename = "model")]
+    Model,
+    #[serde(rename = "text_completion")]
+    TextCompletion,
 }
 
 /// A chat completion choice.
@@ -226,6 +453,16 @@ pub struct Usage {
     total_tokens: u64,
 }
 
+impl From<TokenUsage> for Usage {
+    fn from(usage: TokenUsage) -> Self {
+        Self {
+            completion_tokens: usage.completion_tokens,
+            prompt_tokens: usage.prompt_tokens,
+            total_tokens: usage.total_tokens,
+        }
+    }
+}
+
 // The chat completion chunk object
 // Represents a streamed chunk of a chat completion response returned by model, based on the provided input.
 #[derive(Serialize, Debug, Default)]
@@ -243,7 +480,8 @@ pub struct ChatCompletionChunkResponse {
     /// The object type, which is always chat.completion.chunk.
     object: ObjectKind,
     /// An optional field that will only be present when you set stream_options: {"include_usage": true} in your request. When present, it contains a null value except for the last chunk which contains the token usage statistics for the entire request.
-    usage: Option<Usage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Option<Usage>>,
 }
 
 #[derive(Serialize, Debug, Default)]
@@ -258,6 +496,78 @@ pub struct ChatCompletionChunkChoice {
     delta: JsonValue,
 }
 
+/// The completion object
+/// Represents a completion response from the legacy API, also used for the streamed chunks.
+#[derive(Serialize, Debug, Default)]
+pub struct CompletionResponse {
+    /// A unique identifier for the completion.
+    id: String,
+    /// The list of completion choices the model generated for the input prompt.
+    choices: Vec<CompletionChoice>,
+    /// The Unix timestamp (in seconds) of when the completion was created.
+    created: u64,
+    /// The model used for completion.
+    model: String,
+    /// This fingerprint represents the backend configuration that the model runs with.
+    system_fingerprint: String,
+    /// The object type, which is always "text_completion".
+    object: ObjectKind,
+    /// Usage statistics for the completion request.
+    /// The streamed chunks only have it with stream_options: {"include_usage": true}, null except for the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Option<Usage>>,
+}
+
+/// A completion choice.
+#[derive(Serialize, Debug, Default)]
+pub struct CompletionChoice {
+    /// The generated text.
+    text: String,
+    /// The index of the choice in the list of choices.
+    index: u64,
+    /// The log probabilities, not supported.
+    logprobs: Option<JsonValue>,
+    /// The reason the model stopped generating tokens, `null` until the last chunk when streaming.
+    finish_reason: Option<FinishReason>,
+}
+
+/// The model object
+/// Describes a model offering that can be used with the API, backed by a Dify app.
+#[derive(Serialize, Debug, Default)]
+pub struct ModelObject {
+    /// The model identifier, which can be referenced in the API endpoints.
+    id: String,
+    /// The object type, which is always "model".
+    object: ObjectKind,
+    /// The Unix timestamp (in seconds) when the model was created.
+    created: u64,
+    /// The organization that owns the model.
+    owned_by: String,
+    /// The name of the Dify app.
+    name: String,
+    /// The description of the Dify app.
+    description: String,
+    /// The tags of the Dify app.
+    tags: Vec<String>,
+    /// The parameters of the Dify app (input form, file upload, ...), if available.
+    parameters: Option<JsonValue>,
+}
+
+/// The list of models.
+#[derive(Serialize, Debug, Default)]
+pub struct ModelListResponse {
+    /// The object type, which is always "list".
+    object: ObjectKind,
+    /// The list of model objects.
+    data: Vec<ModelObject>,
+}
+
+/// The input variable of the completion apps receiving the prompt.
+const COMPLETION_QUERY_INPUT: &str = "query";
+
+/// The header carrying the Dify conversation id, in requests and responses.
+const CONVERSATION_ID_HEADER: &str = "x-dify-conversation-id";
+
 /// Extracts the Bearer token from the Authorization header.
 fn get_bearer_token(headers: &HeaderMap) -> Result<String, AppError> {
     let auth_header = headers.get(header::AUTHORIZATION);
@@ -271,6 +581,31 @@ fn get_bearer_token(headers: &HeaderMap) -> Result<String, AppError> {
     Ok(token.to_owned())
 }
 
+/// Authenticates the client of a request by its Bearer token.
+pub(super) fn authenticate(
+    headers: &HeaderMap,
+    gateway: &Gateway,
+    access: &AccessLog,
+) -> Result<Client, ApiError> {
+    let token = get_bearer_token(headers).ok();
+    let client = gateway.keys.authenticate(gateway.config.auth.mode, token)?;
+    access.client(&client);
+    Ok(client)
+}
+
+/// Returns the route of a requested model, if the client may use it.
+fn model_route<'a>(
+    gateway: &'a Gateway,
+    client: &Client,
+    model: &str,
+) -> Result<&'a ModelRoute, ApiError> {
+    gateway
+        .models
+        .get(model)
+        .filter(|route| client.allows(&route.name))
+        .ok_or_else(|| ApiError::model_not_found(model))
+}
+
 /// Sets the Authorization header with a Bearer token.
 fn set_bearer_auth(mut req: HttpRequest, token: &str) -> HttpRequest {
     let mut bearer_auth = header::HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
@@ -279,10 +614,92 @@ fn set_bearer_auth(mut req: HttpRequest, token: &str) -> HttpRequest {
     req
 }
 
-/// Parses a JSON value as a u64.
-/// If the value is not a number, it returns 0.
-fn parse_as_u64(value: Option<&JsonValue>) -> u64 {
-    value.and_then(|v| v.as_u64()).unwrap_or_default()
+/// Sets the Dify conversation id header of a response, if the conversation is known.
+fn with_conversation_id(mut response: Response, conversation_id: &str) -> Response {
+    if let Ok(value) = header::HeaderValue::from_str(conversation_id) {
+        if !conversation_id.is_empty() {
+            response.headers_mut().insert(CONVERSATION_ID_HEADER, value);
+        }
+    }
+    response
+}
+
+/// Describes a model by fetching the app info and parameters from Dify, or from the cache.
+/// Failures are logged and leave the corresponding fields empty,
+/// so that an unreachable endpoint doesn't break the model listing.
+async fn describe_model(
+    cache: &ParametersCache,
+    route: &ModelRoute,
+    token: Option<&str>,
+) -> ModelObject {
+    let model = route.name.as_str();
+    let (info, parameters) = futures::join!(cache.info(route, token), cache.get(route, token));
+    let info = info.unwrap_or_else(|e| {
+        log::warn!("Failed to fetch app info of model {}: {}", model, e);
+        Default::default()
+    });
+    let parameters = parameters
+        .map_err(|e| log::warn!("Failed to fetch app parameters of model {}: {}", model, e))
+        .ok();
+    ModelObject {
+        id: model.to_owned(),
+        object: ObjectKind::Model,
+        created: 0,
+        owned_by: "dify".into(),
+        name: if info.name.is_empty() {
+            model.to_owned()
+        } else {
+            info.name
+        },
+        description: info.description,
+        tags: info.tags,
+        parameters,
+    }
+}
+
+/// Handles the list models request.
+/// This function is called when the client sends a GET request to /models.
+pub async fn models_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(access): Extension<AccessLog>,
+) -> Result<Response, AppError> {
+    let gateway = state.gateway();
+    let client = authenticate(&headers, &gateway, &access)?;
+    let models = gateway
+        .models
+        .iter()
+        .filter(|route| client.allows(&route.name))
+        .map(|route| {
+            let token = client.token(&route.name);
+            let cache = &state.parameters;
+            async move { describe_model(cache, route, token.as_deref()).await }
+        });
+    let response = ModelListResponse {
+        object: ObjectKind::List,
+        data: futures::future::join_all(models).await,
+    };
+    Ok(Json(response).into_response())
+}
+
+/// Handles the retrieve model request.
+/// This function is called when the client sends a GET request to /models/{model}.
+pub async fn model_handler(
+    headers: HeaderMap,
+    Path(model): Path<String>,
+    State(state): State<AppState>,
+    Extension(access): Extension<AccessLog>,
+) -> Result<Response, AppError> {
+    let gateway = state.gateway();
+    let client = authenticate(&headers, &gateway, &access)?;
+    let route = gateway
+        .models
+        .get_exact(&model)
+        .filter(|route| client.allows(&route.name))
+        .ok_or_else(|| ApiError::model_not_found(&model))?;
+    let token = client.token(&route.name);
+    let model = describe_model(&state.parameters, route, token.as_deref()).await;
+    Ok(Json(model).into_response())
 }
 
 /// Handles the chat completions request.
@@ -290,176 +707,1547 @@ fn parse_as_u64(value: Option<&JsonValue>) -> u64 {
 pub async fn chat_completions_handler(
     headers: HeaderMap,
     State(state): State<AppState>,
-    Json(payload): Json<ChatCompletionRequest>,
+    Extension(access): Extension<AccessLog>,
+    AppJson(payload): AppJson<ChatCompletionRequest>,
 ) -> Result<Response, AppError> {
-    // Constructs a query string that includes the talk history and a question.
+    let gateway = state.gateway();
+    let stream = payload.stream.unwrap_or(false);
+    access.model(&payload.model, gateway.models.get(&payload.model), stream);
+    let client = authenticate(&headers, &gateway, &access)?;
+    let model = model_label(&gateway, &payload.model);
+    let cx = model_context(&gateway, &payload.model);
+    let user = payload.user.as_deref();
+    let response = match admit(&state, &gateway, &client, user, stream) {
+        Ok(lease) => {
+            let rate_limit_headers = lease.headers();
+            let answer = chat_completions(headers, state, gateway, client, lease, payload, access)
+                .with_context(cx);
+            let mut response = answer.await.into_response();
+            response.headers_mut().extend(rate_limit_headers);
+            response
+        }
+        Err(limited) => limited.into_response(),
+    };
+    Ok(metrics::with_model(response, model.as_deref()))
+}
+
+/// Returns the name of the model serving a request, for the metrics.
+/// The unknown models are left out, as the clients may send any name.
+fn model_label(gateway: &Gateway, model: &str) -> Option<String> {
+    gateway.models.get(model).map(|route| route.name.clone())
+}
+
+/// Sets the model of a request on its span, returning the context of its Dify calls.
+fn model_context(gateway: &Gateway, model: &str) -> Context {
+    let route = gateway.models.get(model);
+    telemetry::with_model(
+        model,
+        route.map(|route| (route.name.as_str(), &route.app_type)),
+    )
+}
+
+/// Admits a completion request under the rate limits of the client.
+fn admit(
+    state: &AppState,
+    gateway: &Gateway,
+    client: &Client,
+    user: Option<&str>,
+    stream: bool,
+) -> Result<Lease, RateLimited> {
+    let config = &gateway.config.rate_limits;
+    state.rate_limits.admit(config, client, user, stream)
+}
+
+/// Answers a chat completions request admitted under the rate limits.
+async fn chat_completions(
+    headers: HeaderMap,
+    state: AppState,
+    gateway: Arc<Gateway>,
+    client: Client,
+    lease: Lease,
+    payload: ChatCompletionRequest,
+    access: AccessLog,
+) -> Result<Response, AppError> {
+    let route = model_route(&gateway, &client, &payload.model)?;
+    let is_workflow = route.app_type == AppMode::Workflow;
+    if !is_workflow
+        && !matches!(
+            route.app_type,
+            AppMode::Chat | AppMode::AgentChat | AppMode::AdvancedChat
+        )
+    {
+        let message = format!(
+            "The model `{}` is a {:?} app, which doesn't support chat completions.",
+            payload.model, route.app_type
+        );
+        return Err(ApiError::invalid_request(message, Some("model")).into());
+    }
+
+    let tools = ToolSet::from_request(
+        payload.tools,
+        payload.tool_choice,
+        payload.functions,
+        payload.function_call,
+    )?;
+    let n = choice_count(payload.n, &gateway)?;
+    let json_format = JsonFormat::from_request(payload.response_format)?;
+    let stops = payload.stop.map(StringOrArray::into_vec);
+    let max_tokens = payload.max_completion_tokens.or(payload.max_tokens);
+    let limits = AnswerLimits::new(stops.unwrap_or_default(), max_tokens);
+    let user = payload.user.unwrap_or("unknow_user".into());
+    let token = client.token(&route.name);
+    let client_id = client.id();
+
+    let query_span = telemetry::span("build query");
+    // A workflow run has no conversation, and each choice of `n` starts its own.
+    let explicit_conversation_id =
+        explicit_conversation_id(&headers, payload.metadata.as_ref()).filter(|_| !is_workflow);
+    if n > 1 && explicit_conversation_id.is_some() {
+        let message = "A Dify conversation can't be continued with `n` greater than 1.";
+        return Err(ApiError::invalid_request(message, Some("n")).into());
+    }
+    let scope = [
+        route.name.as_str(),
+        user.as_str(),
+        client_id.as_deref().unwrap_or_default(),
+    ];
     let messages = payload.messages;
-    let last_message = messages.last().ok_or(anyhow!("No messages provided"))?;
-    let query_string = format!(
-        "here is our talk history:\n'''\n{}\n'''\n\nhere is my question:\n{}",
-        messages
-            .iter()
-            .take(messages.len() - 1)
-            .map(|message| format!("{}: {}", message.role, message.content))
-            .collect::<Vec<String>>()
-            .join("\n"),
-        last_message.content
+    let conversation = ChatConversation::resolve(
+        &state,
+        &gateway,
+        &messages,
+        &scope,
+        explicit_conversation_id,
+        is_workflow || n > 1,
+    );
+
+    // Constructs a query string from the messages not yet in the Dify conversation.
+    // The system messages are left out when they are sent as inputs.
+    let system = messages
+        .iter()
+        .filter(|message| matches!(message.role, Role::System))
+        .map(|message| message.content.text())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let messages = messages[conversation.start..]
+        .iter()
+        .filter(|message| route.system_input.is_none() || !matches!(message.role, Role::System))
+        .collect::<Vec<_>>();
+    if messages.is_empty() {
+        return Err(ApiError::invalid_request("No messages provided", Some("messages")).into());
+    }
+    let contents = messages
+        .iter()
+        .map(|message| message.text())
+        .collect::<Vec<_>>();
+    let query_messages = messages
+        .iter()
+        .zip(&contents)
+        .map(|(message, content)| (message.role.as_ref(), content.as_ref()))
+        .collect::<Vec<_>>();
+    let query_string = chat_query(
+        &gateway,
+        route,
+        &query_messages,
+        conversation.start > 0,
+        tools.as_ref(),
+        json_format.as_ref(),
+    );
+    telemetry::record_conversation(&query_span, &conversation.conversation_id);
+    let span = query_span.span();
+    span.set_attribute(KeyValue::new("dify.query.messages", messages.len() as i64));
+    span.end();
+
+    let question = query_messages.last().map(|(_, content)| *content);
+    let values = [
+        ("query", query_string.as_str()),
+        ("question", question.unwrap_or_default()),
+        ("system", system.as_str()),
+    ];
+    let inputs = chat_inputs(
+        route,
+        &values,
+        payload.metadata.as_ref(),
+        payload.inputs.as_ref(),
     );
+    state
+        .parameters
+        .validate(route, token.as_deref(), &inputs)
+        .await?;
 
+    let mut api = route.client.api();
+    let custom_token = token.clone();
+    api.before_send(move |mut req| {
+        req.headers_mut()
+            .extend(telemetry::headers(&Context::current()));
+        match custom_token.as_deref() {
+            Some(token) => set_bearer_auth(req, token),
+            None => req,
+        }
+    });
+
+    // The images of the messages are passed to Dify as files.
+    let files = messages
+        .iter()
+        .flat_map(|message| message.content.image_urls())
+        .map(|url| files::image_file(&api, url, &user));
+    let files = futures::future::try_join_all(files).await?;
+
+    let answers = ChatAnswers {
+        state: &state,
+        client: &client,
+        route,
+        token: token.as_deref(),
+        lease,
+        access,
+        // the prompt tokens of a cut answer are estimated from the query and inputs
+        limits: limits.with_prompt(
+            inputs
+                .values()
+                .map(String::as_str)
+                .chain(route.workflow.is_none().then_some(query_string.as_str())),
+        ),
+        n,
+        tools,
+        format: json_format,
+        retries: gateway.config.response_format.retries,
+        model: payload.model,
+        stream: payload.stream.unwrap_or(false),
+        include_usage: StreamOptions::include_usage(payload.stream_options.as_ref()),
+    };
+    if let Some(workflow) = route.workflow.as_ref() {
+        let req_data = WorkflowsRunRequest {
+            inputs,
+            user,
+            files,
+            ..Default::default()
+        };
+        log::debug!("Workflow Run Request: {:?}", Content(&req_data));
+        return answers.workflow(workflow, req_data).await;
+    }
     let req_data = ChatMessagesRequest {
+        inputs,
         query: query_string,
-        user: payload.user.unwrap_or("unknow_user".into()),
+        user,
+        conversation_id: conversation.conversation_id.clone(),
+        files,
         auto_generate_name: false,
         ..Default::default()
     };
-    let mut api = state.dify.api();
-    let token = get_bearer_token(&headers).ok();
-    if let Some(token) = token {
-        log::debug!("User Custom Token: {}", token);
-        api.before_send(move |req| set_bearer_auth(req, token.as_str()));
+    answers.chat(&api, req_data, conversation).await
+}
+
+/// Returns the Dify conversation the client asks to continue,
+/// by the conversation id header or the `conversation_id` of the request metadata.
+fn explicit_conversation_id(
+    headers: &HeaderMap,
+    metadata: Option<&HashMap<String, JsonValue>>,
+) -> Option<String> {
+    headers
+        .get(CONVERSATION_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .or_else(|| metadata?.get("conversation_id")?.as_str().map(String::from))
+}
+
+/// The Dify conversation a chat continues.
+struct ChatConversation {
+    /// The first message not yet in the Dify conversation.
+    start: usize,
+    conversation_id: String,
+    /// Remembers the Dify conversation of the answer, unless the chat is stateless.
+    recorder: Option<ConversationRecorder>,
+}
+
+impl ChatConversation {
+    /// Continues the Dify conversation if the request extends a known one,
+    /// then only the messages after the last answer are sent upstream.
+    fn resolve(
+        state: &AppState,
+        gateway: &Gateway,
+        messages: &[Message],
+        scope: &[&str],
+        explicit_conversation_id: Option<String>,
+        stateless: bool,
+    ) -> Self {
+        let conversations = &gateway.config.conversations;
+        let mut fingerprint = Fingerprint::new(scope);
+        let mut continued: Option<(usize, String)> = None;
+        for (i, message) in messages.iter().enumerate() {
+            fingerprint.push(message.role.as_ref(), &message.text());
+            for url in message.content.image_urls() {
+                fingerprint.push("image_url", url);
+            }
+            let is_answer = matches!(message.role, Role::Assistant) && i + 1 < messages.len();
+            if !is_answer || stateless {
+                continue;
+            }
+            let conversation_id = match explicit_conversation_id.as_ref() {
+                Some(conversation_id) => Some(conversation_id.clone()),
+                None if conversations.enabled => state.conversations.get(fingerprint.value()),
+                None => None,
+            };
+            if let Some(conversation_id) = conversation_id {
+                continued = Some((i + 1, conversation_id));
+            }
+        }
+        let (start, conversation_id) = match continued {
+            Some((start, conversation_id)) => (start, conversation_id),
+            None => (0, explicit_conversation_id.unwrap_or_default()),
+        };
+        let recorder = (conversations.enabled && !stateless).then(|| {
+            ConversationRecorder::new(
+                state.conversations.clone(),
+                fingerprint,
+                conversations.clone(),
+            )
+        });
+        Self {
+            start,
+            conversation_id,
+            recorder,
+        }
     }
+}
 
-    if payload.stream.is_none() || !payload.stream.unwrap() {
-        // Blocking chat completions
-        chat_completions(&api, req_data.clone(), payload.model.as_str()).await
-    } else {
-        // Stream the chat completions
-        chat_completions_stream(&api, req_data.clone(), payload.model.as_str()).await
+/// Builds the Dify query of the messages, with the tools and JSON format prompts if any.
+fn chat_query(
+    gateway: &Gateway,
+    route: &ModelRoute,
+    messages: &[(&str, &str)],
+    continued: bool,
+    tools: Option<&ToolSet>,
+    format: Option<&JsonFormat>,
+) -> String {
+    let mut query_string = route.query.build(messages, continued);
+    if let Some(tools) = tools {
+        query_string = tools.prompt(&route.tools_prompt, &query_string);
+    }
+    if let Some(format) = format {
+        let prompt = gateway.config.response_format.prompt.as_deref();
+        query_string = format.prompt(prompt.unwrap_or(structured::DEFAULT_PROMPT), &query_string);
+    }
+    query_string
+}
+
+/// Collects the Dify inputs: the system messages, then the `metadata` and `inputs` of the request,
+/// then the workflow inputs they don't set, rendered from the `query`, `question` and `system`.
+fn chat_inputs(
+    route: &ModelRoute,
+    values: &[(&str, &str); 3],
+    metadata: Option<&HashMap<String, JsonValue>>,
+    request_inputs: Option<&HashMap<String, JsonValue>>,
+) -> HashMap<String, String> {
+    let [(_, query_string), _, (_, system)] = values;
+    let mut inputs = HashMap::new();
+    if let Some(variable) = route.system_input.as_ref() {
+        if !system.is_empty() {
+            inputs.insert(variable.clone(), system.to_string());
+        }
+    }
+    merge_request_inputs(&mut inputs, metadata, request_inputs);
+    if let Some(workflow) = route.workflow.as_ref() {
+        for (name, template) in &workflow.inputs {
+            let value = || query::render(template, values);
+            inputs.entry(name.clone()).or_insert_with(value);
+        }
+        if !workflow.query_input.is_empty() {
+            let query = || query_string.to_string();
+            inputs
+                .entry(workflow.query_input.clone())
+                .or_insert_with(query);
+        }
+    }
+    inputs
+}
+
+/// Answers the choices of a chat completion, as concurrent Dify requests.
+struct ChatAnswers<'a> {
+    state: &'a AppState,
+    client: &'a Client,
+    route: &'a ModelRoute,
+    token: Option<&'a str>,
+    lease: Lease,
+    access: AccessLog,
+    limits: AnswerLimits,
+    n: usize,
+    tools: Option<ToolSet>,
+    format: Option<JsonFormat>,
+    retries: u32,
+    model: String,
+    stream: bool,
+    include_usage: bool,
+}
+
+impl ChatAnswers<'_> {
+    /// Answers with a workflow run per choice.
+    async fn workflow(
+        self,
+        workflow: &WorkflowConfig,
+        req_data: WorkflowsRunRequest,
+    ) -> Result<Response, AppError> {
+        let (route, token) = (self.route, self.token);
+        let output = workflow.output.clone();
+        // a JSON answer is only sent once validated
+        if !self.stream || self.format.is_some() {
+            let answers = (0..self.n).map(|_| {
+                valid_answer(
+                    self.format.as_ref(),
+                    self.retries,
+                    self.tools.as_ref(),
+                    |feedback| {
+                        let mut req_data = req_data.clone();
+                        let query = req_data.inputs.get_mut(&workflow.query_input);
+                        if let (Some(feedback), Some(query)) = (feedback, query) {
+                            query.push_str(&feedback);
+                        }
+                        workflow_answer(route, token, req_data, output.clone(), &self.limits)
+                    },
+                )
+            });
+            let answers = futures::future::try_join_all(answers).await?;
+            return Ok(self.respond(answers, None));
+        }
+        let streams =
+            (0..self.n).map(|_| dify::workflows_run_stream(&route.client, token, req_data.clone()));
+        let streams = futures::future::try_join_all(streams).await?;
+        let events = streams
+            .into_iter()
+            .map(|stream| {
+                let events = AnswerEvents::workflow(output.clone()).convert_stream(stream);
+                let stop_task = task_stopper(route, token, &req_data.user);
+                self.lease
+                    .meter(self.limits.clone().limit(events, stop_task))
+            })
+            .collect();
+        let guard = task_guard(self.state, self.client, route, token, &req_data.user);
+        Ok(self.stream_response(events, None, Some(guard)))
+    }
+
+    /// Answers with a chat message per choice, continuing the Dify conversation if any.
+    async fn chat(
+        self,
+        api: &Api<'_>,
+        req_data: ChatMessagesRequest,
+        conversation: ChatConversation,
+    ) -> Result<Response, AppError> {
+        let (route, token) = (self.route, self.token);
+        if self.stream && self.format.is_none() {
+            log::debug!(
+                "Chat Completions Streaming Request: {:?}",
+                Content(&req_data)
+            );
+            let streams = (0..self.n)
+                .map(|_| dify::chat_messages_stream(&route.client, token, req_data.clone()));
+            let streams = futures::future::try_join_all(streams).await?;
+            let events = streams
+                .into_iter()
+                .map(|stream| {
+                    let events = AnswerEvents::chat().convert_stream(stream);
+                    let stop_task = task_stopper(route, token, &req_data.user);
+                    self.lease
+                        .meter(self.limits.clone().limit(events, stop_task))
+                })
+                .collect();
+            let guard = task_guard(self.state, self.client, route, token, &req_data.user);
+            let response = self.stream_response(events, conversation.recorder, Some(guard));
+            return Ok(with_conversation_id(
+                response,
+                &conversation.conversation_id,
+            ));
+        }
+
+        // Blocking chat completions, and the JSON answers which are only sent once validated
+        let answers = (0..self.n).map(|_| {
+            valid_answer(
+                self.format.as_ref(),
+                self.retries,
+                self.tools.as_ref(),
+                |feedback| {
+                    let mut req_data = req_data.clone();
+                    if let Some(feedback) = feedback {
+                        req_data.query.push_str(&feedback);
+                    }
+                    chat_answer(route, token, api, req_data, &self.limits)
+                },
+            )
+        });
+        let answers = futures::future::try_join_all(answers).await?;
+        Ok(self.respond(answers, conversation.recorder))
+    }
+
+    /// Sends the answers, as a stream if requested.
+    fn respond(self, answers: Vec<Answer>, recorder: Option<ConversationRecorder>) -> Response {
+        answers
+            .iter()
+            .for_each(|answer| self.lease.charge(answer.usage));
+        let conversation_id = match answers.as_slice() {
+            [answer] => answer.conversation_id.clone().unwrap_or_default(),
+            _ => String::new(),
+        };
+        if self.stream {
+            let events = answers.into_iter().map(Answer::into_events).collect();
+            let response = self.stream_response(events, recorder, None);
+            return with_conversation_id(response, &conversation_id);
+        }
+        answers.iter().for_each(|answer| {
+            metrics::usage(&self.route.name, answer.usage);
+            self.access.usage(answer.usage);
+        });
+        let tools = self.tools.as_ref();
+        let choices = answer_choices(answers, tools);
+        if let Some(recorder) = recorder {
+            let (answer, calls) = &choices[0];
+            recorder.record(&tools::render_calls(&answer.text, calls), &conversation_id);
+        }
+        let response = chat_completion_response(choices, tools, self.model, self.route.reasoning);
+        with_conversation_id(Json(response).into_response(), &conversation_id)
+    }
+
+    /// Streams the answer events of the choices.
+    fn stream_response<S>(
+        self,
+        events: Vec<S>,
+        recorder: Option<ConversationRecorder>,
+        guard: Option<TaskGuard>,
+    ) -> Response
+    where
+        S: Stream<Item = AnswerEvent> + Send + 'static,
+    {
+        chat_completions_stream(
+            events,
+            ChunkBuilder::new(self.model, self.include_usage),
+            self.route.reasoning,
+            self.tools,
+            recorder,
+            guard,
+            StreamMetrics::start(&self.route.name, self.access),
+        )
+    }
+}
+
+/// Gets an answer, checked against the JSON format of the request if any.
+/// An invalid answer is requested again with the validation error, up to `retries` times.
+/// The answers calling tools are not checked.
+async fn valid_answer<F, Fut>(
+    format: Option<&JsonFormat>,
+    retries: u32,
+    tools: Option<&ToolSet>,
+    mut answer: F,
+) -> Result<Answer, AppError>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<Answer, AppError>>,
+{
+    let Some(format) = format else {
+        return answer(None).await;
+    };
+    // the usage covers all the attempts
+    let mut usage = TokenUsage::default();
+    let mut feedback = None;
+    let mut error = String::new();
+    for _ in 0..=retries {
+        let mut attempt = answer(feedback.take()).await?;
+        usage += attempt.usage;
+        attempt.usage = usage;
+        let calls_tools = tools.is_some() && !tools::parse(&attempt.id, &attempt.text).1.is_empty();
+        if attempt.filtered || calls_tools {
+            return Ok(attempt);
+        }
+        match format.extract(&attempt.text) {
+            Ok(json) => {
+                attempt.text = json.to_owned();
+                return Ok(attempt);
+            }
+            Err(e) => {
+                log::debug!("Invalid JSON answer {}: {}", attempt.id, e);
+                feedback = Some(JsonFormat::feedback(&e));
+                error = e;
+            }
+        }
+    }
+    let attempts = retries + 1;
+    let message = format!("No valid JSON answer after {attempts} attempts, {error}.");
+    Err(ApiError::invalid_json_answer(message).into())
+}
+
+/// Returns the number of choices of a request, `n` being capped by the `limits.max_choices` setting.
+fn choice_count(n: Option<u64>, gateway: &Gateway) -> Result<usize, ApiError> {
+    let max = gateway.config.limits.max_choices;
+    match n.unwrap_or(1) {
+        0 => Err(ApiError::invalid_request(
+            "`n` must be at least 1.",
+            Some("n"),
+        )),
+        n if n > max => {
+            let message = format!("`n` must be at most {max}.");
+            Err(ApiError::invalid_request(message, Some("n")))
+        }
+        n => Ok(n as usize),
+    }
+}
+
+/// Parses the tool calls out of the answers of the choices, if the request has tools.
+/// A deprecated `function_call` is a single call.
+fn answer_choices(answers: Vec<Answer>, tools: Option<&ToolSet>) -> Vec<(Answer, Vec<ToolCall>)> {
+    answers
+        .into_iter()
+        .map(|mut answer| {
+            // a moderation reply calls no tools
+            let Some(tools) = tools.filter(|_| !answer.filtered) else {
+                return (answer, Vec::new());
+            };
+            let (text, mut calls) = tools::parse(&answer.id, &answer.text);
+            answer.text = text;
+            if tools.legacy {
+                calls.truncate(1);
+            }
+            (answer, calls)
+        })
+        .collect()
+}
+
+/// Builds a chat completion response, with a choice per answer.
+/// The response takes the id of the first answer, and the usage of all of them.
+fn chat_completion_response(
+    answers: Vec<(Answer, Vec<ToolCall>)>,
+    tools: Option<&ToolSet>,
+    model: String,
+    reasoning: ReasoningMode,
+) -> ChatCompletionResponse {
+    let legacy = tools.is_some_and(|tools| tools.legacy);
+    let id = answers[0].0.id.clone();
+    let created = answers[0].0.created;
+    let mut usage = TokenUsage::default();
+    let choices = answers
+        .into_iter()
+        .enumerate()
+        .map(|(index, (answer, calls))| {
+            usage += answer.usage;
+            let (content, reasoning_content) = match reasoning {
+                _ if answer.reasoning.is_empty() => (answer.text, None),
+                ReasoningMode::ReasoningContent => (answer.text, Some(answer.reasoning)),
+                ReasoningMode::Think => {
+                    let content = format!(
+                        "{THINK_START}{}{THINK_END}{}",
+                        answer.reasoning, answer.text
+                    );
+                    (content, None)
+                }
+                ReasoningMode::Hidden => (answer.text, None),
+            };
+            let (finish_reason, tool_calls, function_call) = match calls.is_empty() {
+                true if answer.filtered => (FinishReason::ContentFilter, None, None),
+                true if answer.truncated => (FinishReason::Length, None, None),
+                true => (FinishReason::Stop, None, None),
+                false if legacy => {
+                    let function = calls.into_iter().next().map(|call| call.function);
+                    (FinishReason::FunctionCall, None, function)
+                }
+                false => (FinishReason::ToolCalls, Some(calls), None),
+            };
+            ChatCompletionChoice {
+                index: index as u64,
+                finish_reason,
+                message: Message {
+                    role: Role::Assistant,
+                    content: content.into(),
+                    reasoning_content,
+                    tool_calls,
+                    function_call,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        })
+        .collect();
+    ChatCompletionResponse {
+        id,
+        choices,
+        created,
+        model,
+        system_fingerprint: String::from("fp_44709d6fcb"),
+        object: ObjectKind::ChatCompletion,
+        usage: usage.into(),
     }
 }
 
 /// Handles the chat completions request.
 /// It uses the `Api` instance from the `AppState` to send a request to the OpenAI API.
-/// It returns a response with the chat completions.
-async fn chat_completions<'a>(
-    api: &Api<'a>,
+/// It returns the answer of the chat completions.
+async fn chat_answer(
+    route: &ModelRoute,
+    token: Option<&str>,
+    api: &Api<'_>,
     req_data: ChatMessagesRequest,
-    model: &'a str,
-) -> Result<Response, AppError> {
-    log::debug!("Chat Completions Block Request: {:?}", req_data);
-    let system_fingerprint = String::from("fp_44709d6fcb");
-    let resp = api.chat_messages(req_data).await.map_err(|e| {
-        e.downcast::<ErrorResponse>()
-            .and_then(|err_resp| Err(anyhow!(err_resp.message)))
-            .unwrap_or_else(|e| anyhow!(e))
-    })?;
-    let usage = resp.metadata.get("usage").unwrap_or(&JsonValue::Null);
-    let response = ChatCompletionResponse {
+    limits: &AnswerLimits,
+) -> Result<Answer, AppError> {
+    if route.app_type == AppMode::AgentChat || limits.is_active() {
+        // Dify agents only answer in streaming mode, and a cut answer is stopped by its task id,
+        // the stream is aggregated
+        log::debug!(
+            "Chat Completions Aggregated Request: {:?}",
+            Content(&req_data)
+        );
+        let stop_task = task_stopper(route, token, &req_data.user);
+        let stream = dify::chat_messages_stream(&route.client, token, req_data).await?;
+        let events = AnswerEvents::chat().convert_stream(stream);
+        return Ok(Answer::collect(limits.clone().limit(events, stop_task)).await?);
+    }
+    log::debug!("Chat Completions Block Request: {:?}", Content(&req_data));
+    audit::dify_request(&req_data);
+    let cx = telemetry::dify_span("POST", "/v1/chat-messages");
+    let resp = match api.chat_messages(req_data).with_context(cx.clone()).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            telemetry::record_failure(&cx, &e);
+            return Err(e.into());
+        }
+    };
+    let metadata = serde_json::json!(resp.metadata);
+    let usage = TokenUsage::from_metadata(&metadata);
+    if let Some(conversation_id) = resp.base.conversation_id.as_deref() {
+        telemetry::record_conversation(&cx, conversation_id);
+    }
+    telemetry::record_usage(&cx, usage);
+    Ok(Answer {
         id: resp.base.message_id,
-        choices: vec![ChatCompletionChoice {
-            message: Message {
-                role: Role::Assistant,
-                content: resp.answer,
-            },
-            ..Default::default()
-        }],
         created: resp.base.created_at,
-        model: model.to_owned(),
-        system_fingerprint,
-        object: ObjectKind::ChatCompletion,
-        usage: Usage {
-            completion_tokens: parse_as_u64(usage.get("completion_tokens")),
-            prompt_tokens: parse_as_u64(usage.get("prompt_tokens")),
-            total_tokens: parse_as_u64(usage.get("total_tokens")),
-        },
-    };
-    return Ok(serde_json::to_string(&response)?.into_response());
+        conversation_id: resp.base.conversation_id,
+        text: resp.answer,
+        usage,
+        ..Default::default()
+    })
 }
 
-/// Handles the chat completions stream request.
-/// It streams the chat completions to the client.
-/// The client can use the stream to display the chat completions in real-time.
-async fn chat_completions_stream<'a>(
-    api: &Api<'a>,
-    req_data: ChatMessagesRequest,
-    model: &'a str,
+/// Handles the chat completions request of a workflow app, in blocking mode.
+/// The answer is the output variable of the workflow run.
+async fn workflow_answer(
+    route: &ModelRoute,
+    token: Option<&str>,
+    req_data: WorkflowsRunRequest,
+    output: Option<String>,
+    limits: &AnswerLimits,
+) -> Result<Answer, AppError> {
+    if limits.is_active() {
+        // a cut answer is stopped by its task id, the stream is aggregated
+        let stop_task = task_stopper(route, token, &req_data.user);
+        let stream = dify::workflows_run_stream(&route.client, token, req_data).await?;
+        let events = AnswerEvents::workflow(output).convert_stream(stream);
+        return Ok(Answer::collect(limits.clone().limit(events, stop_task)).await?);
+    }
+    let resp = dify::workflows_run(&route.client, token, req_data).await?;
+    let run = resp.data;
+    if run.status != "succeeded" {
+        let message = run
+            .error
+            .unwrap_or_else(|| format!("workflow {}", run.status));
+        metrics::upstream_error("workflow_failed");
+        return Err(ApiError::upstream(&ErrorResponse {
+            code: "workflow_failed".into(),
+            message,
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16().into(),
+        })
+        .into());
+    }
+    Ok(Answer {
+        id: resp.workflow_run_id,
+        created: run.created_at,
+        text: workflow_output(&run.outputs, output.as_deref()),
+        usage: TokenUsage::from_total(run.total_tokens),
+        ..Default::default()
+    })
+}
+
+/// Returns a callback stopping the Dify task of an answer cut by the gateway, in the background.
+fn task_stopper(route: &ModelRoute, token: Option<&str>, user: &str) -> impl FnOnce(String) {
+    let stopper = TaskStopper::new(route, token, user);
+    move |task_id| stopper.stop(&task_id)
+}
+
+/// Returns a guard stopping the Dify tasks of a stream dropped by the client.
+fn task_guard(
+    state: &AppState,
+    client: &Client,
+    route: &ModelRoute,
+    token: Option<&str>,
+    user: &str,
+) -> TaskGuard {
+    let stopper = TaskStopper::new(route, token, user);
+    TaskGuard::new(state.tasks.clone(), stopper, client.id())
+}
+
+/// Cancels a streamed chat completion, stopping its Dify tasks.
+/// Only the client which started the completion may cancel it.
+pub async fn cancel_chat_completion_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(access): Extension<AccessLog>,
 ) -> Result<Response, AppError> {
-    log::debug!("Chat Completions Streaming Request: {:?}", req_data);
-    let system_fingerprint = String::from("fp_44709d6fcb");
-    let stream = api.chat_messages_stream(req_data).await?;
-    let model = model.to_owned();
+    let gateway = state.gateway();
+    let owner = authenticate(&headers, &gateway, &access)?.id();
+    if !state.tasks.cancel(&id, owner.as_deref()) {
+        let message = format!("No running chat completion `{id}`.");
+        return Err(ApiError::not_found(message).into());
+    }
+    let response = serde_json::json!({
+        "id": id,
+        "object": "chat.completion.cancelled",
+        "cancelled": true,
+    });
+    Ok(Json(response).into_response())
+}
 
-    let alive_duration = Duration::from_secs(30);
-    let stream_default = stream::iter([SseEvent::default()
-        .comment("streaming chat completions")
-        .retry(alive_duration)]);
-    let stream_msg = stream.map(move |result| match result {
-        Ok(event) => match event {
-            SseMessageEvent::Message {
-                answer, id, base, ..
+/// Builds the chunks of a streamed chat completion.
+struct ChunkBuilder {
+    id: String,
+    created: u64,
+    model: String,
+    /// The usage is requested, the chunks have a null `usage` until the last one.
+    include_usage: bool,
+}
+
+impl ChunkBuilder {
+    /// Creates a builder, the id of the completion is set by its first answer.
+    fn new(model: String, include_usage: bool) -> Self {
+        Self {
+            id: String::new(),
+            created: 0,
+            model,
+            include_usage,
+        }
+    }
+
+    /// Builds a chunk event with a single choice.
+    fn chunk(
+        &self,
+        index: usize,
+        delta: JsonValue,
+        finish_reason: Option<FinishReason>,
+    ) -> SseEvent {
+        let choice = ChatCompletionChunkChoice {
+            index: index as u64,
+            delta,
+            finish_reason,
+            ..Default::default()
+        };
+        self.event(vec![choice], self.include_usage.then_some(None))
+    }
+
+    /// Builds the last chunk event, with the usage of the whole request and no choices.
+    fn usage_chunk(&self, usage: TokenUsage) -> SseEvent {
+        self.event(vec![], Some(Some(usage.into())))
+    }
+
+    fn event(
+        &self,
+        choices: Vec<ChatCompletionChunkChoice>,
+        usage: Option<Option<Usage>>,
+    ) -> SseEvent {
+        let response = ChatCompletionChunkResponse {
+            id: self.id.clone(),
+            choices,
+            created: self.created,
+            model: self.model.clone(),
+            system_fingerprint: String::from("fp_44709d6fcb"),
+            object: ObjectKind::ChatCompletionChunk,
+            usage,
+        };
+        SseEvent::default().json_data(response).unwrap()
+    }
+}
+
+/// The state of a choice of a streamed chat completion.
+struct ChoiceStream {
+    index: usize,
+    reasoning: ReasoningMode,
+    /// Whether a `<think>` block is open.
+    thinking: bool,
+    /// Parses the tool calls of the answer, if the request has tools.
+    parser: Option<ToolCallParser>,
+    /// The deprecated `functions` were given, the first call is sent as a `function_call`.
+    legacy: bool,
+    /// The text of the answer, without the tool calls.
+    text: String,
+    /// The tool calls of the answer.
+    calls: Vec<ToolCall>,
+    /// The answer was cut at the maximum number of tokens.
+    truncated: bool,
+    /// The role was sent, in the first delta.
+    role_sent: bool,
+    /// The answer was replaced by the content moderation.
+    filtered: bool,
+}
+
+impl ChoiceStream {
+    /// Builds a chunk of the choice, the role is only sent in its first delta.
+    fn chunk(
+        &mut self,
+        builder: &ChunkBuilder,
+        mut delta: JsonValue,
+        finish_reason: Option<FinishReason>,
+    ) -> SseEvent {
+        if !self.role_sent {
+            self.role_sent = true;
+            delta["role"] = serde_json::json!(Role::Assistant);
+        }
+        builder.chunk(self.index, delta, finish_reason)
+    }
+
+    /// Builds a content chunk, closing the `<think>` block if open.
+    fn content(&mut self, builder: &ChunkBuilder, mut content: String) -> SseEvent {
+        if self.thinking {
+            self.thinking = false;
+            content.insert_str(0, THINK_END);
+        }
+        let delta = serde_json::json!({
+            "content": content,
+        });
+        self.chunk(builder, delta, None)
+    }
+
+    /// Sends the reasoning of the answer.
+    fn reasoning(&mut self, builder: &ChunkBuilder, text: String) -> Vec<SseEvent> {
+        let delta = match self.reasoning {
+            ReasoningMode::ReasoningContent => serde_json::json!({
+                "reasoning_content": text,
+            }),
+            ReasoningMode::Think if !self.thinking => {
+                self.thinking = true;
+                serde_json::json!({
+                    "content": format!("{THINK_START}{text}"),
+                })
             }
-            | SseMessageEvent::AgentMessage {
-                answer, id, base, ..
-            } => {
-                let base_ref = base.as_ref();
-                let message_id = base_ref.map(|b| b.message_id.clone()).unwrap_or(id);
-                let created_at = base_ref.map(|b| b.created_at.clone()).unwrap_or(0);
-                let response = ChatCompletionChunkResponse {
-                    id: message_id,
-                    choices: vec![ChatCompletionChunkChoice {
-                        delta: serde_json::json!(Message {
-                            role: Role::Assistant,
-                            content: answer,
-                        }),
-                        ..Default::default()
-                    }],
-                    created: created_at,
-                    model: model.clone(),
-                    system_fingerprint: system_fingerprint.clone(),
-                    object: ObjectKind::ChatCompletionChunk,
-                    usage: None,
+            ReasoningMode::Think => serde_json::json!({
+                "content": text,
+            }),
+            ReasoningMode::Hidden => return vec![],
+        };
+        vec![self.chunk(builder, delta, None)]
+    }
+
+    /// Sends the reply of the content moderation, which replaces the answer.
+    /// What was already sent can't be taken back, the finish reason tells it was filtered.
+    fn replace(&mut self, builder: &ChunkBuilder, text: String) -> Vec<SseEvent> {
+        self.filtered = true;
+        self.parser = None;
+        self.text.clone_from(&text);
+        self.calls.clear();
+        vec![self.content(builder, text)]
+    }
+
+    /// Sends a delta of the answer, the tool calls are parsed out of it.
+    fn answer(&mut self, builder: &ChunkBuilder, delta: String) -> Vec<SseEvent> {
+        let parts = match self.parser.as_mut() {
+            Some(parser) => parser.push(&delta),
+            None => vec![AnswerPart::Text(delta)],
+        };
+        self.parts(builder, parts)
+    }
+
+    /// Sends the text and tool calls of the answer.
+    fn parts(&mut self, builder: &ChunkBuilder, parts: Vec<AnswerPart>) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        for part in parts {
+            match part {
+                AnswerPart::Text(text) => {
+                    self.text.push_str(&text);
+                    events.push(self.content(builder, text));
+                }
+                AnswerPart::Call(call) => events.extend(self.call(builder, call)),
+            }
+        }
+        events
+    }
+
+    /// Sends a tool call, as a chunk with its name then a chunk with its arguments.
+    fn call(&mut self, builder: &ChunkBuilder, call: ToolCall) -> Vec<SseEvent> {
+        if self.legacy && !self.calls.is_empty() {
+            return vec![];
+        }
+        let mut events = Vec::new();
+        if self.thinking {
+            events.push(self.content(builder, String::new()));
+        }
+        let (head, arguments) = if self.legacy {
+            let head = serde_json::json!({
+                "content": null,
+                "function_call": {"name": call.function.name, "arguments": ""},
+            });
+            let arguments = serde_json::json!({
+                "function_call": {"arguments": call.function.arguments},
+            });
+            (head, arguments)
+        } else {
+            let index = self.calls.len();
+            let head = serde_json::json!({
+                "content": null,
+                "tool_calls": [{
+                    "index": index,
+                    "id": call.id,
+                    "type": call.type_,
+                    "function": {"name": call.function.name, "arguments": ""},
+                }],
+            });
+            let arguments = serde_json::json!({
+                "tool_calls": [{
+                    "index": index,
+                    "function": {"arguments": call.function.arguments},
+                }],
+            });
+            (head, arguments)
+        };
+        events.push(self.chunk(builder, head, None));
+        events.push(self.chunk(builder, arguments, None));
+        self.calls.push(call);
+        events
+    }
+
+    /// Ends the answer, with the rest of its text then a chunk with its finish reason.
+    fn finish(&mut self, builder: &ChunkBuilder) -> Vec<SseEvent> {
+        let parts = self.parser.as_mut().map(|p| p.finish()).unwrap_or_default();
+        let mut events = self.parts(builder, parts);
+        if self.thinking {
+            events.push(self.content(builder, String::new()));
+        }
+        let finish_reason = match self.calls.is_empty() {
+            true if self.filtered => FinishReason::ContentFilter,
+            true if self.truncated => FinishReason::Length,
+            true => FinishReason::Stop,
+            false if self.legacy => FinishReason::FunctionCall,
+            false => FinishReason::ToolCalls,
+        };
+        let delta = serde_json::json!({});
+        events.push(self.chunk(builder, delta, Some(finish_reason)));
+        events
+    }
+}
+
+/// Renders an upstream error as the last event of a stream, an OpenAI error object.
+fn error_event(err: &ErrorResponse) -> SseEvent {
+    metrics::upstream_error(&err.code);
+    SseEvent::default()
+        .json_data(ApiError::upstream(err).body())
+        .unwrap()
+}
+
+/// Renders the answers of the choices of a stream, driven by `choices_stream`.
+trait ChoicesRenderer: Send + 'static {
+    /// The id of the completion, set by the first answer.
+    fn id(&self) -> &str;
+
+    /// Renders an event of a choice, other than its end or an error.
+    fn event(&mut self, index: usize, event: AnswerEvent) -> Vec<SseEvent>;
+
+    /// Renders the end of a choice, `usage` is `None` if it ended without a Dify end event.
+    fn finish(&mut self, index: usize, usage: Option<TokenUsage>) -> Vec<SseEvent>;
+
+    /// Renders the usage of the whole request, once all the choices are finished.
+    fn usage(&self, usage: TokenUsage) -> SseEvent;
+}
+
+/// Streams the answer events of concurrent choices, interleaved as they come.
+/// The Dify tasks and the metrics of the choices are tracked, the usage is sent once they are
+/// all finished, and an error ends the stream, the other choices being stopped.
+fn choices_stream<S, R>(
+    events: Vec<S>,
+    mut renderer: R,
+    include_usage: bool,
+    mut guard: Option<TaskGuard>,
+    mut metrics: StreamMetrics,
+) -> impl Stream<Item = Option<SseEvent>> + Send + 'static
+where
+    S: Stream<Item = AnswerEvent> + Send + 'static,
+    R: ChoicesRenderer,
+{
+    let mut finished = vec![false; events.len()];
+    let mut unfinished = events.len();
+    let mut total_usage = TokenUsage::default();
+
+    let streams = events.into_iter().enumerate().map(|(index, events)| {
+        // the end of the events of a choice is marked by `None`
+        let events = futures::StreamExt::map(events, Some).chain(stream::once(async { None }));
+        Box::pin(futures::StreamExt::map(events, move |event| (index, event)))
+    });
+    futures::StreamExt::flat_map(stream::select_all(streams), move |(index, event)| {
+        let mut events = Vec::new();
+        let mut finishing = false;
+        match event {
+            None if finished[index] => {}
+            None => {
+                // the choice ended without a Dify end event, e.g. once cancelled, its task is over
+                if let Some(guard) = guard.as_mut() {
+                    guard.finished(index);
+                }
+                finished[index] = true;
+                unfinished -= 1;
+                finishing = true;
+                events = renderer.finish(index, None);
+            }
+            Some(AnswerEvent::Finished(usage)) => {
+                if let Some(guard) = guard.as_mut() {
+                    guard.finished(index);
+                }
+                finished[index] = true;
+                unfinished -= 1;
+                finishing = true;
+                metrics.usage(usage);
+                total_usage += usage;
+                events = renderer.finish(index, Some(usage));
+            }
+            Some(AnswerEvent::Error(err)) => {
+                if let Some(guard) = guard.as_mut() {
+                    guard.finished(index);
+                }
+                return stream::iter(vec![Some(error_event(&err)), None]);
+            }
+            Some(AnswerEvent::Progress(comment)) => {
+                events.push(SseEvent::default().comment(comment));
+            }
+            Some(event) => {
+                let task_id = match &event {
+                    AnswerEvent::Started { task_id, .. } => Some(task_id.clone()),
+                    AnswerEvent::Text(_) | AnswerEvent::Reasoning(_) | AnswerEvent::Replaced(_) => {
+                        metrics.token();
+                        None
+                    }
+                    _ => None,
                 };
-                SseEvent::default().json_data(response).unwrap()
+                events = renderer.event(index, event);
+                if let (Some(guard), Some(task_id)) = (guard.as_mut(), task_id) {
+                    guard.started(index, renderer.id(), &task_id);
+                }
             }
-            SseMessageEvent::MessageEnd {
-                id, base, metadata, ..
+        }
+        if finishing && unfinished == 0 && include_usage {
+            events.push(renderer.usage(std::mem::take(&mut total_usage)));
+        }
+        stream::iter(events.into_iter().map(Some).collect::<Vec<_>>())
+    })
+}
+
+/// Renders the choices of a streamed chat completion.
+struct ChatChoices {
+    builder: ChunkBuilder,
+    choices: Vec<ChoiceStream>,
+    /// Remembers the Dify conversation of the answers.
+    recorder: Option<ConversationRecorder>,
+    conversation_id: String,
+}
+
+impl ChoicesRenderer for ChatChoices {
+    fn id(&self) -> &str {
+        &self.builder.id
+    }
+
+    fn event(&mut self, index: usize, event: AnswerEvent) -> Vec<SseEvent> {
+        let choice = &mut self.choices[index];
+        match event {
+            AnswerEvent::Started {
+                id,
+                created,
+                conversation_id,
+                ..
             } => {
-                let base_ref = base.as_ref();
-                let message_id = base_ref.map(|b| b.message_id.clone()).unwrap_or(id);
-                let created_at = base_ref.map(|b| b.created_at.clone()).unwrap_or(0);
-                let usage = metadata.get("usage").unwrap_or(&JsonValue::Null);
-                let response = ChatCompletionChunkResponse {
-                    id: message_id,
-                    choices: vec![ChatCompletionChunkChoice {
-                        delta: serde_json::json!({}),
-                        finish_reason: Some(FinishReason::Stop),
-                        ..Default::default()
-                    }],
-                    created: created_at,
-                    model: model.clone(),
-                    system_fingerprint: system_fingerprint.clone(),
-                    object: ObjectKind::ChatCompletionChunk,
-                    usage: Some(Usage {
-                        completion_tokens: parse_as_u64(usage.get("completion_tokens")),
-                        prompt_tokens: parse_as_u64(usage.get("prompt_tokens")),
-                        total_tokens: parse_as_u64(usage.get("total_tokens")),
+                if let Some(parser) = choice.parser.as_mut() {
+                    parser.set_answer_id(&id);
+                }
+                if self.builder.id.is_empty() {
+                    self.builder.id = id;
+                    self.builder.created = created;
+                }
+                self.conversation_id = conversation_id.unwrap_or_default();
+                vec![]
+            }
+            AnswerEvent::Reasoning(text) => choice.reasoning(&self.builder, text),
+            AnswerEvent::Text(answer) => choice.answer(&self.builder, answer),
+            AnswerEvent::Replaced(text) => choice.replace(&self.builder, text),
+            AnswerEvent::Truncated => {
+                choice.truncated = true;
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn finish(&mut self, index: usize, usage: Option<TokenUsage>) -> Vec<SseEvent> {
+        let choice = &mut self.choices[index];
+        let events = choice.finish(&self.builder);
+        // the conversation is remembered once an answer is complete
+        if let (Some(recorder), Some(_)) = (self.recorder.as_ref(), usage) {
+            let answer = tools::render_calls(&choice.text, &choice.calls);
+            recorder.record(&answer, &self.conversation_id);
+        }
+        events
+    }
+
+    fn usage(&self, usage: TokenUsage) -> SseEvent {
+        self.builder.usage_chunk(usage)
+    }
+}
+
+/// Handles the chat completions stream request.
+/// It streams the chat completions to the client, a stream of answer events per choice.
+/// The client can use the stream to display the chat completions in real-time.
+fn chat_completions_stream<S>(
+    events: Vec<S>,
+    builder: ChunkBuilder,
+    reasoning: ReasoningMode,
+    tools: Option<ToolSet>,
+    recorder: Option<ConversationRecorder>,
+    guard: Option<TaskGuard>,
+    metrics: StreamMetrics,
+) -> Response
+where
+    S: Stream<Item = AnswerEvent> + Send + 'static,
+{
+    let legacy = tools.as_ref().is_some_and(|tools| tools.legacy);
+    let choices = (0..events.len())
+        .map(|index| ChoiceStream {
+            index,
+            reasoning,
+            thinking: false,
+            parser: tools.as_ref().map(|_| ToolCallParser::new("")),
+            legacy,
+            text: String::new(),
+            calls: Vec::new(),
+            truncated: false,
+            role_sent: false,
+            filtered: false,
+        })
+        .collect::<Vec<_>>();
+    let include_usage = builder.include_usage;
+    let renderer = ChatChoices {
+        builder,
+        choices,
+        recorder,
+        conversation_id: String::new(),
+    };
+    let events = choices_stream(events, renderer, include_usage, guard, metrics);
+    sse_response("streaming chat completions", events)
+}
+
+/// Sends the events as an SSE response, terminated by `[DONE]`.
+/// A `None` event ends the response without `[DONE]`, after an error event.
+fn sse_response(
+    comment: &'static str,
+    events: impl Stream<Item = Option<SseEvent>> + Send + 'static,
+) -> Response {
+    let alive_duration = Duration::from_secs(30);
+    let stream_default = stream::iter([Some(
+        SseEvent::default().comment(comment).retry(alive_duration),
+    )]);
+    let stream_end = stream::iter([Some(SseEvent::default().data("[DONE]"))]);
+    let stream = stream_default
+        .chain(events)
+        .chain(stream_end)
+        .map_while(|event| event);
+    Sse::new(stream.map(Ok::<_, AnyError>))
+        .keep_alive(KeepAlive::default().interval(alive_duration))
+        .into_response()
+}
+
+/// Handles the legacy completions request, on the Dify completion apps.
+/// This function is called when the client sends a POST request to /completions.
+pub async fn completions_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(access): Extension<AccessLog>,
+    AppJson(payload): AppJson<CompletionRequest>,
+) -> Result<Response, AppError> {
+    let gateway = state.gateway();
+    let stream = payload.stream.unwrap_or(false);
+    access.model(&payload.model, gateway.models.get(&payload.model), stream);
+    let client = authenticate(&headers, &gateway, &access)?;
+    let model = model_label(&gateway, &payload.model);
+    let cx = model_context(&gateway, &payload.model);
+    let user = payload.user.as_deref();
+    let response = match admit(&state, &gateway, &client, user, stream) {
+        Ok(lease) => {
+            let rate_limit_headers = lease.headers();
+            let answer =
+                completions(state, gateway, client, lease, payload, access).with_context(cx);
+            let mut response = answer.await.into_response();
+            response.headers_mut().extend(rate_limit_headers);
+            response
+        }
+        Err(limited) => limited.into_response(),
+    };
+    Ok(metrics::with_model(response, model.as_deref()))
+}
+
+/// Answers a legacy completions request admitted under the rate limits.
+async fn completions(
+    state: AppState,
+    gateway: Arc<Gateway>,
+    client: Client,
+    lease: Lease,
+    payload: CompletionRequest,
+    access: AccessLog,
+) -> Result<Response, AppError> {
+    let route = model_route(&gateway, &client, &payload.model)?;
+    if route.app_type != AppMode::Completion {
+        let message = format!(
+            "The model `{}` is a {:?} app, which doesn't support completions.",
+            payload.model, route.app_type
+        );
+        return Err(ApiError::invalid_request(message, Some("model")).into());
+    }
+
+    let user = payload.user.unwrap_or("unknow_user".into());
+    let token = client.token(&route.name);
+    let n = choice_count(payload.n, &gateway)?;
+    let prompts = payload.prompt.into_vec();
+    if prompts.is_empty() {
+        return Err(ApiError::invalid_request("No prompt provided", Some("prompt")).into());
+    }
+    // each prompt gets `n` choices, requested concurrently
+    let prompts = prompts
+        .into_iter()
+        .flat_map(|prompt| std::iter::repeat(prompt).take(n))
+        .collect::<Vec<_>>();
+    let stops = payload
+        .stop
+        .map(StringOrArray::into_vec)
+        .unwrap_or_default();
+    let echo = payload.echo.unwrap_or(false);
+
+    let mut inputs = HashMap::new();
+    if let Some(suffix) = payload.suffix {
+        inputs.insert("suffix".to_owned(), suffix);
+    }
+    merge_request_inputs(
+        &mut inputs,
+        payload.metadata.as_ref(),
+        payload.inputs.as_ref(),
+    );
+    let requests = prompts
+        .iter()
+        .map(|prompt| {
+            let mut inputs = inputs.clone();
+            inputs.insert(COMPLETION_QUERY_INPUT.to_owned(), prompt.clone());
+            CompletionMessagesRequest {
+                inputs,
+                user: user.clone(),
+                ..Default::default()
+            }
+        })
+        .collect::<Vec<_>>();
+    state
+        .parameters
+        .validate(route, token.as_deref(), &requests[0].inputs)
+        .await?;
+    log::debug!("Completions Request: {:?}", Content(&requests));
+
+    let token = token.as_deref();
+    let model = payload.model;
+    let limits = AnswerLimits::new(stops, payload.max_tokens);
+    if !payload.stream.unwrap_or(false) {
+        let answers = requests
+            .into_iter()
+            .map(|req_data| completion_answer(route, token, req_data, &limits));
+        let answers = futures::future::try_join_all(answers).await?;
+        answers.iter().for_each(|answer| lease.charge(answer.usage));
+        let mut usage = TokenUsage::default();
+        let choices = answers
+            .iter()
+            .zip(&prompts)
+            .enumerate()
+            .map(|(index, (answer, prompt))| {
+                usage += answer.usage;
+                CompletionChoice {
+                    text: if echo {
+                        format!("{prompt}{}", answer.text)
+                    } else {
+                        answer.text.clone()
+                    },
+                    index: index as u64,
+                    logprobs: None,
+                    finish_reason: Some(if answer.filtered {
+                        FinishReason::ContentFilter
+                    } else if answer.truncated {
+                        FinishReason::Length
+                    } else {
+                        FinishReason::Stop
                     }),
-                };
-                SseEvent::default().json_data(response).unwrap()
+                }
+            })
+            .collect();
+        metrics::usage(&route.name, usage);
+        access.usage(usage);
+        let response = CompletionResponse {
+            id: answers[0].id.clone(),
+            choices,
+            created: answers[0].created,
+            model,
+            system_fingerprint: String::from("fp_44709d6fcb"),
+            object: ObjectKind::TextCompletion,
+            usage: Some(Some(usage.into())),
+        };
+        return Ok(Json(response).into_response());
+    }
+
+    let limits = requests
+        .iter()
+        .map(|req_data| limits.clone().with_prompt(completion_prompt(req_data)))
+        .collect::<Vec<_>>();
+    let streams = requests
+        .into_iter()
+        .map(|req_data| dify::completion_messages_stream(&route.client, token, req_data));
+    let streams = futures::future::try_join_all(streams).await?;
+    let streams = streams.into_iter().zip(limits).map(|(stream, limits)| {
+        let events = AnswerEvents::chat().convert_stream(stream);
+        let events = limits.limit(events, task_stopper(route, token, &user));
+        lease.meter(events)
+    });
+    let choices = prompts
+        .into_iter()
+        .map(|prompt| CompletionChoiceStream {
+            echo: echo.then_some(prompt),
+            finish_reason: None,
+        })
+        .collect();
+    let renderer = CompletionChoices {
+        id: String::new(),
+        created: 0,
+        model,
+        include_usage: StreamOptions::include_usage(payload.stream_options.as_ref()),
+        choices,
+    };
+    let include_usage = renderer.include_usage;
+    let guard = task_guard(&state, &client, route, token, &user);
+    let metrics = StreamMetrics::start(&route.name, access);
+    let events = choices_stream(
+        streams.collect(),
+        renderer,
+        include_usage,
+        Some(guard),
+        metrics,
+    );
+    Ok(sse_response("streaming completions", events))
+}
+
+/// The state of a choice of a streamed completion.
+struct CompletionChoiceStream {
+    /// The prompt to echo, sent before the answer.
+    echo: Option<String>,
+    /// The finish reason, unless `stop`.
+    finish_reason: Option<FinishReason>,
+}
+
+/// Renders the choices of a streamed completion.
+struct CompletionChoices {
+    id: String,
+    created: u64,
+    model: String,
+    /// The usage is requested, the chunks have a null `usage` until the last one.
+    include_usage: bool,
+    choices: Vec<CompletionChoiceStream>,
+}
+
+impl CompletionChoices {
+    fn event(&self, choices: Vec<CompletionChoice>, usage: Option<Option<Usage>>) -> SseEvent {
+        let response = CompletionResponse {
+            id: self.id.clone(),
+            choices,
+            created: self.created,
+            model: self.model.clone(),
+            system_fingerprint: String::from("fp_44709d6fcb"),
+            object: ObjectKind::TextCompletion,
+            usage,
+        };
+        SseEvent::default().json_data(response).unwrap()
+    }
+
+    /// Builds a chunk with the text or the finish reason of a choice.
+    fn chunk(
+        &self,
+        index: usize,
+        text: String,
+        finish_reason: Option<FinishReason>,
+    ) -> Vec<SseEvent> {
+        if text.is_empty() && finish_reason.is_none() {
+            return vec![];
+        }
+        let choice = CompletionChoice {
+            text,
+            index: index as u64,
+            logprobs: None,
+            finish_reason,
+        };
+        vec![self.event(vec![choice], self.include_usage.then_some(None))]
+    }
+}
+
+impl ChoicesRenderer for CompletionChoices {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn event(&mut self, index: usize, event: AnswerEvent) -> Vec<SseEvent> {
+        match event {
+            AnswerEvent::Started { id, created, .. } => {
+                if self.id.is_empty() {
+                    self.id = id;
+                    self.created = created;
+                }
+                let echo = self.choices[index].echo.take().unwrap_or_default();
+                self.chunk(index, echo, None)
             }
-            SseMessageEvent::Error { message, .. } => {
-                let message = format!("upstream: {message}");
-                let err = serde_json::json!({ "error": {"message":message} });
-                SseEvent::default().json_data(err).unwrap()
+            AnswerEvent::Text(text) => self.chunk(index, text, None),
+            AnswerEvent::Truncated => {
+                self.choices[index].finish_reason = Some(FinishReason::Length);
+                vec![]
             }
-            _ => {
-                let event_name = serde_json::json!(event)
-                    .get("event")
-                    .map(|v| v.to_string())
-                    .unwrap_or("unknown".into());
-                let comment = format!("skip dify message event: {}", event_name.trim_matches('"'));
-                SseEvent::default().comment(comment)
+            AnswerEvent::Replaced(text) => {
+                self.choices[index].finish_reason = Some(FinishReason::ContentFilter);
+                self.chunk(index, text, None)
             }
-        },
-        Err(e) => {
-            let message = format!("upstream: {}", e.to_string());
-            let err = serde_json::json!({ "error": {"message": message }});
-            SseEvent::default().json_data(err).unwrap()
+            _ => vec![],
         }
-    });
-    let stream_end = stream::iter([SseEvent::default().data("[DONE]")]);
-    let stream = stream_default.chain(stream_msg).chain(stream_end);
-    Ok(Sse::new(stream.map(Ok::<_, AnyError>))
-        .keep_alive(KeepAlive::default().interval(alive_duration))
-        .into_response())
+    }
+
+    fn finish(&mut self, index: usize, _usage: Option<TokenUsage>) -> Vec<SseEvent> {
+        let finish_reason = self.choices[index].finish_reason.take();
+        self.chunk(
+            index,
+            String::new(),
+            finish_reason.or(Some(FinishReason::Stop)),
+        )
+    }
+
+    fn usage(&self, usage: TokenUsage) -> SseEvent {
+        self.event(vec![], Some(Some(usage.into())))
+    }
+}
+
+/// The prompt of a completion request, its inputs with the prompt of the client.
+fn completion_prompt(req_data: &CompletionMessagesRequest) -> impl Iterator<Item = &str> {
+    req_data.inputs.values().map(String::as_str)
+}
+
+/// Sends a completion request, in blocking mode unless the answer may be cut.
+async fn completion_answer(
+    route: &ModelRoute,
+    token: Option<&str>,
+    req_data: CompletionMessagesRequest,
+    limits: &AnswerLimits,
+) -> Result<Answer, AppError> {
+    if limits.is_active() {
+        // a cut answer is stopped by its task id, the stream is aggregated
+        let stop_task = task_stopper(route, token, &req_data.user);
+        let limits = limits.clone().with_prompt(completion_prompt(&req_data));
+        let stream = dify::completion_messages_stream(&route.client, token, req_data).await?;
+        let events = AnswerEvents::chat().convert_stream(stream);
+        return Ok(Answer::collect(limits.limit(events, stop_task)).await?);
+    }
+    let resp = dify::completion_messages(&route.client, token, req_data).await?;
+    Ok(Answer {
+        id: resp.message_id,
+        created: resp.created_at,
+        text: resp.answer,
+        usage: TokenUsage::from_metadata(&resp.metadata),
+        ..Default::default()
+    })
 }